@@ -0,0 +1,119 @@
+/*!
+Mock of a Ref Finance compatible DEX used to test swap distributions.
+NOTES:
+  - Tokens are deposited with `ft_transfer_call` and credited to the sender.
+  - Every pool swaps at the fixed `rate`, in percent of the input amount.
+  - `withdraw` sends the deposit of the caller with `ft_transfer`. Swap outputs are only credited,
+    so the pool account must hold enough of the output token for withdrawals.
+*/
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    env, ext_contract, near_bindgen, AccountId, Balance, Gas, PanicOnDefault, PromiseOrValue,
+};
+
+const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub amount_in: Option<U128>,
+    pub token_out: AccountId,
+    pub min_amount_out: U128,
+}
+
+#[ext_contract(ext_ft)]
+pub trait FungibleToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct Contract {
+    rate: u32,
+    deposits: LookupMap<(AccountId, AccountId), Balance>,
+}
+
+#[near_bindgen]
+impl Contract {
+    #[init]
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            deposits: LookupMap::new(b"d".to_vec()),
+        }
+    }
+
+    /// Credit the received tokens to `sender_id`.
+    pub fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        assert!(msg.is_empty(), "Only deposits are supported");
+        let token_id = env::predecessor_account_id();
+        self.credit(&sender_id, &token_id, amount.0);
+        PromiseOrValue::Value(0.into())
+    }
+
+    /// Swap the deposits of the caller and return the output of the last action.
+    #[payable]
+    pub fn swap(&mut self, actions: Vec<SwapAction>, referral_id: Option<AccountId>) -> U128 {
+        assert_eq!(
+            env::attached_deposit(),
+            1,
+            "Requires attached deposit of exactly 1 yoctoNEAR"
+        );
+        let _ = referral_id;
+        let account_id = env::predecessor_account_id();
+        let mut amount_out = 0;
+        for action in actions {
+            let amount_in = action.amount_in.map(|a| a.0).unwrap_or(amount_out);
+            self.debit(&account_id, &action.token_in, amount_in);
+            amount_out = amount_in * self.rate as u128 / 100;
+            assert!(amount_out >= action.min_amount_out.0, "Slippage error");
+            self.credit(&account_id, &action.token_out, amount_out);
+        }
+        amount_out.into()
+    }
+
+    /// Send `amount` of the deposit of the caller in `token_id` back to it.
+    #[payable]
+    pub fn withdraw(&mut self, token_id: AccountId, amount: U128, unregister: Option<bool>) {
+        assert_eq!(
+            env::attached_deposit(),
+            1,
+            "Requires attached deposit of exactly 1 yoctoNEAR"
+        );
+        let _ = unregister;
+        let account_id = env::predecessor_account_id();
+        self.debit(&account_id, &token_id, amount.0);
+        ext_ft::ft_transfer(account_id, amount, None, token_id, 1, GAS_FOR_FT_TRANSFER);
+    }
+
+    /// Return the deposit of `account_id` in `token_id`.
+    pub fn get_deposit(&self, account_id: AccountId, token_id: AccountId) -> U128 {
+        self.deposits
+            .get(&(account_id, token_id))
+            .unwrap_or(0)
+            .into()
+    }
+
+    fn credit(&mut self, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        let key = (account_id.clone(), token_id.clone());
+        let balance = self.deposits.get(&key).unwrap_or(0);
+        self.deposits.insert(&key, &(balance + amount));
+    }
+
+    fn debit(&mut self, account_id: &AccountId, token_id: &AccountId, amount: Balance) {
+        let key = (account_id.clone(), token_id.clone());
+        let balance = self.deposits.get(&key).unwrap_or(0);
+        assert!(balance >= amount, "Not enough deposit");
+        self.deposits.insert(&key, &(balance - amount));
+    }
+}
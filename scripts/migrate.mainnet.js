@@ -21,17 +21,15 @@ const shell = require('shelljs');
                 transactions.deployContract(fs.readFileSync(env.WASM_PATH)),
                 transactions.functionCall(
                     "migrate",
-                    Buffer.from(JSON.stringify({})),
-                    200000000000000,
-                    // "1"
-                ),
-                transactions.functionCall(
-                    "set_oracle",
                     Buffer.from(JSON.stringify({
-                        "oracle_account_id": env.mainnet.ORACLE_ACCOUNT_ID,
-                        "oracle_provider_id": env.mainnet.ORACLE_PROVIDER_ID,
+                        "oracle_mode": {
+                            "mode": "oracle",
+                            "oracle_account_id": env.mainnet.ORACLE_ACCOUNT_ID,
+                            "provider_id": env.mainnet.ORACLE_PROVIDER_ID,
+                        },
                     })),
-                    30000000000000,
+                    200000000000000,
+                    // "1"
                 ),
             ],
         });
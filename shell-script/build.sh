@@ -1,3 +1,6 @@
 #!/bin/bash
 
 raen build --release
+# mock DEX used by the swap simulation tests
+cargo build -p mock-pool --target wasm32-unknown-unknown --release
+cp target/wasm32-unknown-unknown/release/mock_pool.wasm target/res/
@@ -0,0 +1,67 @@
+use crate::*;
+
+/// Discount that exempts an account from fees and quota.
+pub const FULL_DISCOUNT_PERCENT: u8 = 100;
+
+#[near_bindgen]
+impl Contract {
+    /// Give `account_id` a discount on service fees and subscriptions. A discount of 100
+    /// exempts the account, its distributions don't use quota. A discount of 0 removes it.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner.
+    /// - `discount_percent` must not exceed 100.
+    pub fn set_fee_discount(&mut self, account_id: AccountId, discount_percent: u8) {
+        self.assert_owner();
+        assert!(
+            discount_percent <= FULL_DISCOUNT_PERCENT,
+            "discount must not exceed {} percent",
+            FULL_DISCOUNT_PERCENT
+        );
+        let old_discount = if discount_percent == 0 {
+            self.fee_discounts.remove(&account_id)
+        } else {
+            self.fee_discounts.insert(&account_id, &discount_percent)
+        };
+
+        let discount_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::SetFeeDiscount(SetFeeDiscountLog {
+                account_id: account_id.to_string(),
+                old_discount: old_discount.unwrap_or_default(),
+                new_discount: discount_percent,
+                owner_id: self.owner_id.to_string(),
+            }),
+        };
+        env::log_str(&discount_log.to_string());
+    }
+
+    /// Return the fee discount of `account_id` in percent.
+    pub fn fee_discount_of(&self, account_id: AccountId) -> u8 {
+        self.fee_discounts.get(&account_id).unwrap_or_default()
+    }
+
+    /// Return the accounts with a fee discount, paginated.
+    pub fn fee_discounts(
+        &self,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<(AccountId, u8)> {
+        self.fee_discounts
+            .iter()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .collect()
+    }
+
+    pub(crate) fn is_fee_exempt(&self, account_id: &AccountId) -> bool {
+        self.fee_discounts.get(account_id) == Some(FULL_DISCOUNT_PERCENT)
+    }
+
+    /// Apply the fee discount of `account_id` to `amount`.
+    pub(crate) fn discounted_fee(&self, account_id: &AccountId, amount: Balance) -> Balance {
+        let discount = self.fee_discounts.get(account_id).unwrap_or_default() as u128;
+        amount * (FULL_DISCOUNT_PERCENT as u128 - discount) / FULL_DISCOUNT_PERCENT as u128
+    }
+}
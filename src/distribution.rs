@@ -0,0 +1,364 @@
+use crate::*;
+use std::collections::HashMap;
+
+uint::construct_uint! {
+    /// 256-bit integer to scale amounts by a weight without overflowing.
+    struct U256(4);
+}
+
+/// How the deposit of a batch is split across the receivers.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum SplitMode {
+    /// Every receiver gets the same share, `amount` must be empty.
+    Equal,
+    /// `amount` holds the weight of each receiver, e.g. basis points.
+    Weighted,
+}
+
+impl SplitMode {
+    /// Split `total` across `receivers` receivers. Shares are rounded down, the remainder is
+    /// left to the caller to return to the sender.
+    pub fn split(&self, total: Balance, receivers: usize, amount: &[U128]) -> Vec<U128> {
+        assert!(receivers > 0, "receivers must not be empty");
+        let shares: Vec<U128> = match self {
+            SplitMode::Equal => {
+                assert!(amount.is_empty(), "amount must be empty for an equal split");
+                vec![(total / receivers as u128).into(); receivers]
+            }
+            SplitMode::Weighted => {
+                assert_eq!(receivers, amount.len(), "invalid parameters");
+                let total_weight = amount
+                    .iter()
+                    .try_fold(0u128, |sum, weight| sum.checked_add(weight.0))
+                    .expect("Sum of weights overflows");
+                assert!(total_weight > 0, "weights must not all be zero");
+                // A share never exceeds `total` as each weight is at most the sum of weights.
+                amount
+                    .iter()
+                    .map(|weight| {
+                        (U256::from(total) * U256::from(weight.0) / U256::from(total_weight))
+                            .as_u128()
+                            .into()
+                    })
+                    .collect()
+            }
+        };
+        assert!(
+            shares.iter().all(|share| share.0 > 0),
+            "Amount too small to split"
+        );
+        shares
+    }
+}
+
+/// How receivers listed more than once in a batch are handled.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateMode {
+    /// Send one transfer per entry, the default.
+    Allow,
+    /// Merge the entries of a receiver into one transfer of their total.
+    Aggregate,
+    /// Reject batches with duplicate receivers.
+    Reject,
+}
+
+impl DuplicateMode {
+    /// Apply the mode to the batch of `sender_id`. Merged receivers keep the position of their
+    /// first entry and are reported in a `merge_receivers` event.
+    pub fn apply(
+        &self,
+        sender_id: &AccountId,
+        receivers: Vec<AccountId>,
+        amount: Vec<U128>,
+    ) -> (Vec<AccountId>, Vec<U128>) {
+        if *self == DuplicateMode::Allow {
+            return (receivers, amount);
+        }
+        assert_eq!(receivers.len(), amount.len(), "invalid parameters");
+        let total_receivers = receivers.len();
+        let mut positions: HashMap<AccountId, usize> = HashMap::new();
+        let mut unique_receivers: Vec<AccountId> = Vec::new();
+        let mut unique_amount: Vec<U128> = Vec::new();
+        for (receiver_id, transfer_amount) in receivers.into_iter().zip(amount) {
+            match positions.get(&receiver_id) {
+                Some(&i) => {
+                    assert!(
+                        *self != DuplicateMode::Reject,
+                        "Duplicate receiver {}",
+                        receiver_id
+                    );
+                    unique_amount[i] = (unique_amount[i].0 + transfer_amount.0).into();
+                }
+                None => {
+                    positions.insert(receiver_id.clone(), unique_receivers.len());
+                    unique_receivers.push(receiver_id);
+                    unique_amount.push(transfer_amount);
+                }
+            }
+        }
+        if unique_receivers.len() < total_receivers {
+            let merge_log: EventLog = EventLog {
+                standard: EVENT_STANDARD_NAME.to_string(),
+                version: EVENT_VERSION.to_string(),
+                event: EventLogVariant::MergeReceivers(MergeReceiversLog {
+                    sender_id: sender_id.to_string(),
+                    receivers: total_receivers as u64,
+                    unique_receivers: unique_receivers.len() as u64,
+                }),
+            };
+            env::log_str(&merge_log.to_string());
+        }
+        (unique_receivers, unique_amount)
+    }
+}
+
+/// Which receivers a NEAR batch accepts. A transfer to an implicit account that does not exist
+/// creates it, while a transfer to a missing named account fails and is refunded by
+/// `callback_transfer_near`. Contracts cannot look accounts up synchronously, so
+/// `RejectMissingNamed` checks named receivers with a zero transfer before sending any NEAR.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum AccountPolicy {
+    /// Send to implicit accounts, creating them if needed, the default.
+    AllowImplicit,
+    /// Reject batches with an implicit account.
+    RejectImplicit,
+    /// Reject the whole batch if a named receiver does not exist, see `named_account_checks`.
+    RejectMissingNamed,
+}
+
+impl AccountPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccountPolicy::AllowImplicit => "allow_implicit",
+            AccountPolicy::RejectImplicit => "reject_implicit",
+            AccountPolicy::RejectMissingNamed => "reject_missing_named",
+        }
+    }
+
+    pub fn check(&self, receivers: &[AccountId]) {
+        if *self == AccountPolicy::RejectImplicit {
+            let implicit = receivers.iter().find(|id| is_implicit_account(id));
+            assert!(
+                implicit.is_none(),
+                "Implicit account {} is rejected",
+                implicit.unwrap()
+            );
+        }
+    }
+}
+
+/// Zero transfers to the named `receivers` in one joint promise, `None` if all are implicit.
+/// A transfer to a missing named account fails, so the results tell which of them exist.
+pub(crate) fn named_account_checks(receivers: &[AccountId]) -> Option<Promise> {
+    receivers
+        .iter()
+        .filter(|account_id| !is_implicit_account(account_id))
+        .map(|account_id| Promise::new(account_id.clone()).transfer(0))
+        .reduce(|checks, check| checks.and(check))
+}
+
+/// The named `receivers` whose check by `named_account_checks` failed.
+pub(crate) fn missing_named_accounts(receivers: &[AccountId]) -> Vec<AccountId> {
+    let named: Vec<&AccountId> = receivers
+        .iter()
+        .filter(|account_id| !is_implicit_account(account_id))
+        .collect();
+    assert_eq!(env::promise_results_count(), named.len() as u64);
+    named
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| !matches!(env::promise_result(*i as u64), PromiseResult::Successful(_)))
+        .map(|(_, account_id)| account_id.clone())
+        .collect()
+}
+
+/// Whether `account_id` is an implicit account, i.e. 64 lowercase hex characters.
+pub fn is_implicit_account(account_id: &AccountId) -> bool {
+    let account_id = account_id.as_str();
+    account_id.len() == 64
+        && account_id
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+/// Optional settings of a distribution batch.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DistributeOptions {
+    /// Account whose quota pays for the batch. The sender must have an allowance from it,
+    /// see `approve_quota_spender`. Defaults to the sender.
+    #[serde(default)]
+    pub quota_owner_id: Option<AccountId>,
+    /// Block timestamp in nanoseconds before which the batch is held by the contract, see
+    /// `release_escrow`.
+    #[serde(default)]
+    pub release_at: Option<U64>,
+    /// Split the deposit instead of sending the given amounts. The remainder of the rounding
+    /// is returned to the sender.
+    #[serde(default)]
+    pub split: Option<SplitMode>,
+    /// How duplicate receivers are handled, quota is charged per receiver after merging.
+    #[serde(default)]
+    pub duplicates: Option<DuplicateMode>,
+    /// Which receivers a NEAR batch accepts, see `AccountPolicy`.
+    #[serde(default)]
+    pub account_policy: Option<AccountPolicy>,
+    /// Send a NEAR batch as wrapped NEAR with `ft_transfer`. The attached deposit also covers
+    /// the wrapped NEAR storage of every receiver, see `wrap_token`, unused storage is refunded.
+    #[serde(default)]
+    pub wrap_near: bool,
+}
+
+/// JSON form of the `ft_transfer_call` message, mirroring the `distribute_near` arguments.
+/// With `list_id` the receivers of that finalized recipient list are used instead, in chunks
+/// of `limit` entries from `from_index` like `distribute_near_from_list`, the rest of the list
+/// without `limit`. With
+/// `schedule_id` the tokens fund that schedule. With `vesting` the tokens are held and vest
+/// to the receivers instead of being transferred. With `swap` the tokens are swapped first and
+/// `amount` is sent in the output token.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FtDistributeMsg {
+    #[serde(default)]
+    pub receivers: Vec<AccountId>,
+    #[serde(default)]
+    pub amount: Vec<U128>,
+    #[serde(default)]
+    pub list_id: Option<u64>,
+    #[serde(default)]
+    pub from_index: u64,
+    #[serde(default)]
+    pub limit: Option<u64>,
+    #[serde(default)]
+    pub schedule_id: Option<u64>,
+    #[serde(default)]
+    pub vesting: Option<VestingTerms>,
+    #[serde(default)]
+    pub swap: Option<SwapStep>,
+    #[serde(default)]
+    pub options: DistributeOptions,
+}
+
+impl FtDistributeMsg {
+    /// Parse the message of `ft_transfer_call`, either JSON or the `"bob.testnet:20#alice.testnet:50"` format.
+    pub fn parse(msg: &str) -> Result<Self, String> {
+        if msg.trim_start().starts_with('{') {
+            return serde_json::from_str(msg)
+                .map_err(|err| format!("Invalid distribution message: {}", err));
+        }
+        let mut receivers: Vec<AccountId> = Vec::new();
+        let mut amount: Vec<U128> = Vec::new();
+        for receiver_info in msg.split('#') {
+            let (account_id, transfer_amount) = receiver_info.split_once(':').ok_or_else(|| {
+                format!(
+                    "Invalid distribution entry \"{}\", expected \"account_id:amount\"",
+                    receiver_info
+                )
+            })?;
+            let account_id: AccountId = account_id
+                .parse()
+                .map_err(|_| format!("Invalid receiver account id \"{}\"", account_id))?;
+            let transfer_amount: u128 = transfer_amount.parse().map_err(|_| {
+                format!(
+                    "Invalid amount \"{}\" for receiver {}",
+                    transfer_amount, account_id
+                )
+            })?;
+            receivers.push(account_id);
+            amount.push(transfer_amount.into());
+        }
+        Ok(Self {
+            receivers,
+            amount,
+            list_id: None,
+            from_index: 0,
+            limit: None,
+            schedule_id: None,
+            vesting: None,
+            swap: None,
+            options: DistributeOptions::default(),
+        })
+    }
+}
+
+/// Transfer `amount[i]` of NEAR to `receivers[i]` in one joint promise.
+pub(crate) fn near_transfers(receivers: &[AccountId], amount: &[U128]) -> Promise {
+    let mut transfer_promise = Promise::new(receivers[0].clone()).transfer(amount[0].0);
+    for i in 1..receivers.len() {
+        transfer_promise =
+            transfer_promise.and(Promise::new(receivers[i].clone()).transfer(amount[i].0));
+    }
+    transfer_promise
+}
+
+/// Transfer `amount[i]` of `token_id` to `receivers[i]` in one joint promise.
+pub(crate) fn ft_transfers(
+    token_id: &AccountId,
+    receivers: &[AccountId],
+    amount: &[U128],
+) -> Promise {
+    let mut ft_transfer_promise = ext_ft::ft_transfer(
+        receivers[0].clone(),
+        amount[0],
+        Some(String::from("")),
+        token_id.clone(),
+        1, // yocto NEAR to attach
+        GAS_FOR_FT_TRANSFER,
+    );
+    for i in 1..receivers.len() {
+        ft_transfer_promise = ft_transfer_promise.and(ext_ft::ft_transfer(
+            receivers[i].clone(),
+            amount[i],
+            Some(String::from("")),
+            token_id.clone(),
+            1, // yocto NEAR to attach
+            GAS_FOR_FT_TRANSFER,
+        ));
+    }
+    ft_transfer_promise
+}
+
+/// Read the results of a transfer batch in its callback and return the amount and the
+/// number of transfers that failed.
+pub(crate) fn failed_transfers(amount: &[U128]) -> (Balance, u128) {
+    assert_eq!(env::promise_results_count(), amount.len() as u64);
+    let mut refund: Balance = 0;
+    let mut total_failed_transfer: u128 = 0;
+    for (i, transfer_amount) in amount.iter().enumerate() {
+        match env::promise_result(i as u64) {
+            PromiseResult::NotReady => {
+                log!(" Transfer not ready ");
+            }
+            PromiseResult::Failed => {
+                refund += transfer_amount.0;
+                total_failed_transfer += 1;
+            }
+            PromiseResult::Successful(_) => {}
+        };
+    }
+    (refund, total_failed_transfer)
+}
+
+/// Whether the `storage_balance_of` query at `index` found a registered account.
+pub(crate) fn is_registered(index: u64) -> bool {
+    registration_result(index).unwrap_or(false)
+}
+
+/// Whether the `storage_balance_of` query at `index` found a registered account, `None` if the
+/// query failed.
+pub(crate) fn registration_result(index: u64) -> Option<bool> {
+    match env::promise_result(index) {
+        PromiseResult::Successful(result) => {
+            serde_json::from_slice::<Option<StorageBalance>>(&result)
+                .ok()
+                .map(|balance| balance.is_some())
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,191 @@
+use crate::*;
+
+/// A distribution held by the contract until `release_at`. Its quota and storage are charged
+/// when it is escrowed, failed transfers are refunded to the sender after release.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Escrow {
+    /// Token contract of a NEP-141 distribution, `None` for NEAR.
+    pub token_id: Option<AccountId>,
+    pub receivers: Vec<AccountId>,
+    pub amount: Vec<U128>,
+    /// Block timestamp in nanoseconds from which the distribution can be released.
+    pub release_at: U64,
+    pub charge: QuotaCharge,
+}
+
+impl Escrow {
+    pub fn total_amount(&self) -> Balance {
+        self.amount.iter().map(|x| x.0).sum()
+    }
+
+    /// The NEP-297 event `event` of this escrow.
+    fn log(&self, escrow_id: u64, event: fn(EscrowLog) -> EventLogVariant) -> EventLog {
+        EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: event(EscrowLog {
+                escrow_id,
+                sender_id: self.charge.spender_id.to_string(),
+                token_id: self.token_id.as_ref().map(|token_id| token_id.to_string()),
+                receivers: self.receivers.len() as u64,
+                total_amount: self.total_amount().to_string(),
+                release_at: self.release_at.0.to_string(),
+            }),
+        }
+    }
+}
+
+/// An escrowed distribution with its id.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EscrowView {
+    pub escrow_id: u64,
+    #[serde(flatten)]
+    pub escrow: Escrow,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Send the escrowed distribution `escrow_id` to its receivers. Anyone can call it once
+    /// `release_at` is reached.
+    pub fn release_escrow(&mut self, escrow_id: u64) -> Promise {
+        let escrow = self.escrows.get(&escrow_id).expect("Escrow not found");
+        let feature = match escrow.token_id {
+            Some(_) => Feature::DistributeFt,
+            None => Feature::DistributeNear,
+        };
+        self.assert_not_paused(feature);
+        assert!(
+            env::block_timestamp() >= escrow.release_at.0,
+            "Escrow is not released yet"
+        );
+        self.remove_escrow(&escrow, escrow_id);
+        if escrow.token_id.is_none() {
+            self.release_held_near(escrow.total_amount());
+        }
+        env::log_str(
+            &escrow
+                .log(escrow_id, EventLogVariant::ReleaseEscrow)
+                .to_string(),
+        );
+
+        let transfer_promise = match &escrow.token_id {
+            Some(token_id) => ft_transfers(token_id, &escrow.receivers, &escrow.amount),
+            None => near_transfers(&escrow.receivers, &escrow.amount),
+        };
+        transfer_promise.then(ext_self::callback_transfer_funds(
+            escrow.token_id,
+            escrow.amount,
+            escrow.charge,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_TRANSFER_FUNDS_CALLBACK,
+        ))
+    }
+
+    /// Cancel the escrowed distribution `escrow_id` and refund the funds and quota.
+    ///
+    /// Requirements:
+    /// - The caller must be the sender of the distribution.
+    /// - `release_at` must not be reached.
+    pub fn cancel_escrow(&mut self, escrow_id: u64) {
+        let escrow = self.escrows.get(&escrow_id).expect("Escrow not found");
+        assert_eq!(
+            env::predecessor_account_id(),
+            escrow.charge.spender_id,
+            "Only the sender can cancel the escrow"
+        );
+        assert!(
+            env::block_timestamp() < escrow.release_at.0,
+            "Escrow is already released"
+        );
+        self.remove_escrow(&escrow, escrow_id);
+        let total_amount = escrow.total_amount();
+        if escrow.token_id.is_none() {
+            self.release_held_near(total_amount);
+        }
+        env::log_str(
+            &escrow
+                .log(escrow_id, EventLogVariant::CancelEscrow)
+                .to_string(),
+        );
+        self.refund_funds(escrow.token_id, &escrow.charge.spender_id, total_amount);
+        self.refund_quota(&escrow.charge, escrow.charge.amount);
+    }
+
+    /// Return the escrowed distribution `escrow_id`.
+    pub fn get_escrow(&self, escrow_id: u64) -> Option<Escrow> {
+        self.escrows.get(&escrow_id)
+    }
+
+    /// Return the escrowed distributions of `sender_id`, paginated.
+    pub fn escrows_of(
+        &self,
+        sender_id: AccountId,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<EscrowView> {
+        self.escrow_ids
+            .get(&sender_id)
+            .unwrap_or_default()
+            .into_iter()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .map(|escrow_id| EscrowView {
+                escrow_id,
+                escrow: self.escrows.get(&escrow_id).unwrap(),
+            })
+            .collect()
+    }
+
+    /// Hold a distribution until `release_at` and return its escrow id.
+    pub(crate) fn create_escrow(
+        &mut self,
+        token_id: Option<AccountId>,
+        receivers: Vec<AccountId>,
+        amount: Vec<U128>,
+        release_at: U64,
+        charge: QuotaCharge,
+    ) -> u64 {
+        let initial_storage_usage = env::storage_usage();
+        let sender_id = charge.spender_id.clone();
+        let escrow_id = self.next_escrow_id;
+        self.next_escrow_id += 1;
+        let escrow = Escrow {
+            token_id,
+            receivers,
+            amount,
+            release_at,
+            charge,
+        };
+        if escrow.token_id.is_none() {
+            self.hold_near(escrow.total_amount());
+        }
+        self.escrows.insert(&escrow_id, &escrow);
+        let mut escrow_ids = self.escrow_ids.get(&sender_id).unwrap_or_default();
+        escrow_ids.push(escrow_id);
+        self.escrow_ids.insert(&sender_id, &escrow_ids);
+        self.charge_storage(&sender_id, initial_storage_usage);
+        env::log_str(
+            &escrow
+                .log(escrow_id, EventLogVariant::CreateEscrow)
+                .to_string(),
+        );
+        escrow_id
+    }
+
+    fn remove_escrow(&mut self, escrow: &Escrow, escrow_id: u64) {
+        let sender_id = &escrow.charge.spender_id;
+        let initial_storage_usage = env::storage_usage();
+        self.escrows.remove(&escrow_id);
+        let mut escrow_ids = self.escrow_ids.get(sender_id).unwrap_or_default();
+        escrow_ids.retain(|id| *id != escrow_id);
+        if escrow_ids.is_empty() {
+            self.escrow_ids.remove(sender_id);
+        } else {
+            self.escrow_ids.insert(sender_id, &escrow_ids);
+        }
+        self.record_storage(sender_id, initial_storage_usage);
+    }
+}
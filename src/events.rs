@@ -2,7 +2,7 @@ use crate::*;
 use near_sdk::serde_json;
 use std::fmt;
 
-pub const EVENT_STANDARD_NAME: &str = "nep297"; 
+pub const EVENT_STANDARD_NAME: &str = "nep297";
 pub const EVENT_VERSION: &str = "1.0.0";
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -15,6 +15,24 @@ pub enum EventLogVariant {
     SetOracleId(SetOracleIdLog),
     PayFee(PayFeeLog),
     RefundNear(RefundNearLog),
+    SetPaused(SetPausedLog),
+    WithdrawFees(WithdrawFeesLog),
+    ReferralReward(ReferralRewardLog),
+    ClaimReferralRewards(ClaimReferralRewardsLog),
+    TransferQuota(TransferQuotaLog),
+    ApproveQuotaSpender(ApproveQuotaSpenderLog),
+    Subscribe(SubscribeLog),
+    RedeemQuota(RedeemQuotaLog),
+    SetFeeDiscount(SetFeeDiscountLog),
+    MergeReceivers(MergeReceiversLog),
+    DistributeNear(DistributeNearLog),
+    RejectMissingAccounts(RejectMissingAccountsLog),
+    CreateEscrow(EscrowLog),
+    ReleaseEscrow(EscrowLog),
+    CancelEscrow(EscrowLog),
+    StageCode(StageCodeLog),
+    CancelStagedCode(CancelStagedCodeLog),
+    DeployStagedCode(DeployStagedCodeLog),
 }
 
 /// Interface to capture data about an event
@@ -75,3 +93,134 @@ pub struct RefundNearLog {
     pub refund_amount: String,
     pub user_id: String,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SetPausedLog {
+    pub feature: String,
+    pub paused: bool,
+    pub account_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawFeesLog {
+    pub amount: String,
+    pub receiver_id: String,
+    pub account_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReferralRewardLog {
+    pub amount: String,
+    pub referrer_id: String,
+    pub user_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ClaimReferralRewardsLog {
+    pub amount: String,
+    pub referrer_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TransferQuotaLog {
+    pub amount: String,
+    pub sender_id: String,
+    pub receiver_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ApproveQuotaSpenderLog {
+    pub amount: String,
+    pub owner_id: String,
+    pub spender_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SubscribeLog {
+    pub amount: String,
+    pub refund: String,
+    pub user_id: String,
+    pub plan_id: u64,
+    pub expires_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RedeemQuotaLog {
+    pub amount: String,
+    pub refund: String,
+    pub fee: String,
+    pub user_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SetFeeDiscountLog {
+    pub account_id: String,
+    pub old_discount: u8,
+    pub new_discount: u8,
+    pub owner_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MergeReceiversLog {
+    pub sender_id: String,
+    pub receivers: u64,
+    pub unique_receivers: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DistributeNearLog {
+    pub sender_id: String,
+    pub receivers: u64,
+    pub total_amount: String,
+    pub account_policy: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RejectMissingAccountsLog {
+    pub sender_id: String,
+    pub missing_receivers: Vec<String>,
+    pub refund_amount: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EscrowLog {
+    pub escrow_id: u64,
+    pub sender_id: String,
+    /// Token contract of a NEP-141 distribution, `None` for NEAR.
+    pub token_id: Option<String>,
+    pub receivers: u64,
+    pub total_amount: String,
+    pub release_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StageCodeLog {
+    pub code_hash: String,
+    pub deployable_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct CancelStagedCodeLog {
+    pub code_hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DeployStagedCodeLog {
+    pub code_hash: String,
+}
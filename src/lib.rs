@@ -1,6 +1,6 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LookupMap;
-use near_sdk::json_types::U128;
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet, Vector};
+use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::serde_json::json;
 use near_sdk::{
@@ -8,17 +8,61 @@ use near_sdk::{
     PanicOnDefault, Promise, PromiseOrValue, PromiseResult, StorageUsage,
 };
 
+pub use crate::discount::*;
+pub use crate::distribution::*;
+pub use crate::escrow::*;
 pub use crate::events::*;
+pub use crate::list::*;
+pub use crate::migration::*;
+pub use crate::oracle::*;
+pub use crate::pause::*;
+pub use crate::quota::*;
+pub use crate::redemption::*;
+pub use crate::referral::*;
+pub use crate::schedule::*;
+pub use crate::storage::*;
+pub use crate::subscription::*;
+pub use crate::swap::*;
+pub use crate::treasury::*;
+pub use crate::upgrade::*;
+pub use crate::validation::*;
+pub use crate::vesting::*;
+pub use crate::wrap::*;
 
+mod discount;
+mod distribution;
+mod escrow;
 mod events;
+mod list;
+mod migration;
+mod oracle;
+mod pause;
+mod quota;
+mod redemption;
+mod referral;
+mod refund;
+mod schedule;
+mod storage;
+mod subscription;
+mod swap;
+mod treasury;
+mod upgrade;
+mod validation;
+mod vesting;
+mod wrap;
 
 const NO_DEPOSIT: Balance = 0;
 const ONE_NEAR: Balance = 1_000_000_000_000_000_000_000_000;
 const GAS_FOR_TRANSFER_NEAR_CALLBACK: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_CHECK_ACCOUNTS_CALLBACK: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_CHECKED_TRANSFER: Gas = Gas(1_000_000_000_000);
 const GAS_FOR_FT_TRANSFER: Gas = Gas(2_000_000_000_000);
 const GAS_FOR_FT_TRANSFER_CALLBACK: Gas = Gas(3_000_000_000_000);
+const GAS_FOR_TRANSFER_FUNDS_CALLBACK: Gas = Gas(15_000_000_000_000);
 const GAS_FOR_STORAGE_DEPOSIT_CALLBACK: Gas = Gas(3_000_000_000_000);
+const GAS_FOR_GET_ENTRY_CALLBACK: Gas = Gas(10_000_000_000_000);
 const GAS_FOR_STORAGE_DEPOSIT: Gas = Gas(2_000_000_000_000);
+const GAS_FOR_STORAGE_BALANCE_OF: Gas = Gas(2_000_000_000_000);
 const FEE_USD_PER_ADDRESS_DECIMAL_OFFSET: u32 = 2;
 const FEE_USD_PER_ADDRESS_VALUE: u128 = 5;
 
@@ -30,6 +74,7 @@ pub trait FungibleToken {
         account_id: Option<AccountId>,
         registration_only: Option<bool>,
     ) -> StorageBalance;
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance>;
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
@@ -67,24 +112,115 @@ pub trait StorageManagement {
 
 #[ext_contract(ext_self)]
 pub trait Handler {
-    fn callback_storage_deposit(&self, account_ids: Vec<AccountId>, min_fee: U128);
-    fn callback_transfer_near(&self, receivers: Vec<AccountId>, amount: Vec<U128>);
-    fn callback_ft_transfer(&self, account_ids: Vec<AccountId>, amount: Vec<U128>) -> U128;
-    fn callback_get_entry(&self, estimated_fee: U128, amount: Balance);
-}
-
-#[derive(BorshDeserialize, BorshSerialize)]
-pub struct OldContract {
-    pub owner_id: AccountId,  // admin Id
-    pub oracle_id: AccountId, // oracleId
-    pub balances: LookupMap<AccountId, u128>,
-    pub account_storage_usage: StorageUsage,
-    pub service_fee: U128,
+    fn callback_storage_deposit(
+        &self,
+        sender_id: AccountId,
+        account_ids: Vec<AccountId>,
+        min_fee: U128,
+    );
+    fn callback_transfer_near(
+        &self,
+        receivers: Vec<AccountId>,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+    );
+    fn callback_check_named_accounts(
+        &self,
+        receivers: Vec<AccountId>,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+    );
+    fn callback_ft_transfer(
+        &self,
+        account_ids: Vec<AccountId>,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+        unused_amount: U128,
+    ) -> U128;
+    fn callback_get_entry(
+        &self,
+        account_id: AccountId,
+        estimated_fee: U128,
+        amount: Balance,
+        referrer_id: Option<AccountId>,
+    );
+    fn callback_withdraw_fees(&self, amount: U128, receiver_id: AccountId);
+    fn callback_claim_referral_rewards(&self, referrer_id: AccountId, amount: U128);
+    fn callback_subscribe(
+        &self,
+        plan_id: u64,
+        account_id: AccountId,
+        amount: U128,
+        referrer_id: Option<AccountId>,
+    );
+    fn callback_redeem_quota(&self, account_id: AccountId, amount: u128, lots: Vec<QuotaLot>);
+    fn callback_execute_schedule(
+        &self,
+        schedule_id: u64,
+        period: u64,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+    );
+    fn callback_withdraw_vested(&self, account_id: AccountId, withdrawals: Vec<(u64, U128)>);
+    fn callback_validate_batch(&self, report: BatchReport) -> BatchReport;
+    fn callback_wrap_near(
+        &self,
+        receivers: Vec<AccountId>,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+        storage_deposit: U128,
+    );
+    fn callback_wnear_registered(
+        &self,
+        receivers: Vec<AccountId>,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+        registrations: u64,
+    );
+    fn callback_wnear_transfer(&self, amount: Vec<U128>, charge: QuotaCharge);
+    fn callback_unwrap_refund(&self, sender_id: AccountId, amount: U128);
+    fn callback_wrap_token_registered(&self, wrap_token_id: AccountId, storage_deposit: U128);
+    fn callback_swap_deposit(&self, swap: PendingSwap) -> U128;
+    fn callback_swap(&self, swap: PendingSwap);
+    fn callback_swap_withdraw(&self, swap: PendingSwap, amount_out: U128);
+    fn callback_swap_refund(
+        &self,
+        dex_id: AccountId,
+        token_id: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+    );
+    fn callback_transfer_funds(
+        &self,
+        token_id: Option<AccountId>,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+    );
+    fn callback_refund_funds(&self, token_id: AccountId, account_id: AccountId, amount: U128);
 }
 
 #[derive(BorshSerialize, BorshStorageKey)]
 enum StorageKey {
     BalanceData,
+    Pausers,
+    Referrals,
+    QuotaAllowances,
+    SubscriptionPlans,
+    Subscriptions,
+    QuotaLots,
+    FeeDiscounts,
+    RecipientLists,
+    ListEntries { list_id: u64 },
+    Schedules,
+    ScheduledLists,
+    SchedulePeriods,
+    Vestings,
+    VestingIds,
+    Escrows,
+    StorageAccounts,
+    DexRefunds,
+    TokenRefunds,
+    EscrowIds,
 }
 
 #[near_bindgen]
@@ -92,42 +228,118 @@ enum StorageKey {
 pub struct Contract {
     pub owner_id: AccountId, // admin Id
     pub balances: LookupMap<AccountId, u128>,
-    pub oracle_account_id: AccountId,
-    pub oracle_provider_id: AccountId,
+    pub oracle_mode: OracleMode,
+    pub paused: PauseStatus,
+    pub pauser_ids: UnorderedSet<AccountId>,
+    pub collected_fees: Balance,
+    pub withdrawn_fees: Balance,
+    pub treasury_id: Option<AccountId>,
+    pub referral_fee_bps: u16,
+    pub referrals: LookupMap<AccountId, ReferralAccount>,
+    pub quota_allowances: LookupMap<(AccountId, AccountId), u128>,
+    pub plans: UnorderedMap<u64, SubscriptionPlan>,
+    pub next_plan_id: u64,
+    pub subscriptions: LookupMap<AccountId, Subscription>,
+    pub quota_lots: LookupMap<AccountId, Vec<QuotaLot>>,
+    pub redemption_fee_bps: u16,
+    pub fee_discounts: UnorderedMap<AccountId, u8>,
+    pub lists: LookupMap<u64, RecipientList>,
+    pub next_list_id: u64,
+    pub schedules: LookupMap<u64, Schedule>,
+    pub next_schedule_id: u64,
+    /// Number of active schedules of each recipient list.
+    pub scheduled_lists: LookupMap<u64, u32>,
+    pub schedule_periods: LookupMap<(u64, u64), PeriodResult>,
+    pub vestings: LookupMap<u64, Vesting>,
+    pub next_vesting_id: u64,
+    /// Vesting ids of each beneficiary.
+    pub vesting_ids: LookupMap<AccountId, Vec<u64>>,
+    pub escrows: LookupMap<u64, Escrow>,
+    pub next_escrow_id: u64,
+    pub wrap_token_id: AccountId,
+    pub wrap_storage_deposit: Balance,
+    /// Whether the contract is registered on `wrap_token_id`, see `set_wrap_token`.
+    pub wrap_token_registered: bool,
+    pub dex_id: Option<AccountId>,
+    pub storage_accounts: LookupMap<AccountId, StorageAccount>,
+    /// Storage in bytes of the registration of one account.
+    pub account_storage_usage: StorageUsage,
+    pub oracle_config: OracleConfig,
+    /// Tokens of failed swap distributions left on a DEX, by sender, DEX and token.
+    pub dex_refunds: LookupMap<(AccountId, AccountId, AccountId), Balance>,
+    /// NEAR owed to users: escrowed and scheduled NEAR, referral rewards and storage balances.
+    pub held_near: Balance,
+    /// Tokens of failed refunds, by account and token, see `claim_token_refund`.
+    pub token_refunds: LookupMap<(AccountId, AccountId), Balance>,
+    /// Escrow ids of each sender.
+    pub escrow_ids: LookupMap<AccountId, Vec<u64>>,
 }
 
 #[near_bindgen]
 impl Contract {
     #[init]
     pub fn new(oracle_account_id: AccountId, oracle_provider_id: AccountId) -> Self {
-        let this = Self {
-            owner_id: env::signer_account_id(),
-            balances: LookupMap::new(StorageKey::BalanceData),
+        Self::init(OracleMode::Oracle(FluxSource {
             oracle_account_id,
-            oracle_provider_id,
-        };
-        this
+            provider_id: oracle_provider_id,
+        }))
     }
 
-    #[init(ignore_state)]
-    pub fn migrate() -> Self {
-        let old_state: OldContract = env::state_read().expect("failed");
-
-        // Check the caller is authorized to update the code
-        assert!(
-            env::predecessor_account_id() == old_state.owner_id,
-            "Only owner can update the code"
-        );
+    /// Initialize the contract without an oracle, charging `yocto_per_address` per address.
+    #[init]
+    pub fn new_with_fixed_price(yocto_per_address: U128) -> Self {
+        assert!(yocto_per_address.0 > 0, "fixed price must be positive");
+        Self::init(OracleMode::FixedPrice { yocto_per_address })
+    }
 
-        Self {
-            owner_id: old_state.owner_id, // admin Id
-            balances: old_state.balances,
-            oracle_account_id: AccountId::new_unchecked("oracle_account_id".to_string()),
-            oracle_provider_id: AccountId::new_unchecked("oracle_provider_id".to_string()),
-        }
+    fn init(oracle_mode: OracleMode) -> Self {
+        let mut this = Self {
+            owner_id: env::signer_account_id(),
+            balances: LookupMap::new(StorageKey::BalanceData),
+            oracle_mode,
+            paused: PauseStatus::default(),
+            pauser_ids: UnorderedSet::new(StorageKey::Pausers),
+            collected_fees: 0,
+            withdrawn_fees: 0,
+            treasury_id: None,
+            referral_fee_bps: 0,
+            referrals: LookupMap::new(StorageKey::Referrals),
+            quota_allowances: LookupMap::new(StorageKey::QuotaAllowances),
+            plans: UnorderedMap::new(StorageKey::SubscriptionPlans),
+            next_plan_id: 0,
+            subscriptions: LookupMap::new(StorageKey::Subscriptions),
+            quota_lots: LookupMap::new(StorageKey::QuotaLots),
+            redemption_fee_bps: 0,
+            fee_discounts: UnorderedMap::new(StorageKey::FeeDiscounts),
+            lists: LookupMap::new(StorageKey::RecipientLists),
+            next_list_id: 0,
+            schedules: LookupMap::new(StorageKey::Schedules),
+            next_schedule_id: 0,
+            scheduled_lists: LookupMap::new(StorageKey::ScheduledLists),
+            schedule_periods: LookupMap::new(StorageKey::SchedulePeriods),
+            vestings: LookupMap::new(StorageKey::Vestings),
+            next_vesting_id: 0,
+            vesting_ids: LookupMap::new(StorageKey::VestingIds),
+            escrows: LookupMap::new(StorageKey::Escrows),
+            next_escrow_id: 0,
+            wrap_token_id: AccountId::new_unchecked("wrap.near".to_string()),
+            wrap_storage_deposit: DEFAULT_WRAP_STORAGE_DEPOSIT,
+            wrap_token_registered: false,
+            dex_id: None,
+            storage_accounts: LookupMap::new(StorageKey::StorageAccounts),
+            account_storage_usage: 0,
+            oracle_config: OracleConfig::default(),
+            dex_refunds: LookupMap::new(StorageKey::DexRefunds),
+            held_near: 0,
+            token_refunds: LookupMap::new(StorageKey::TokenRefunds),
+            escrow_ids: LookupMap::new(StorageKey::EscrowIds),
+        };
+        this.measure_account_storage_usage();
+        write_state_version();
+        this
     }
 
-    /// Set the oracle account id and  oracle provider id.
+    /// Set the oracle account id and  oracle provider id, switching to oracle mode.
     ///
     /// Requirements:
     /// - The caller must be contract owners.
@@ -146,20 +358,22 @@ impl Contract {
             self.owner_id,
             "only contract owner can set oracle"
         );
-        self.oracle_account_id = oracle_account_id;
-        self.oracle_provider_id = oracle_provider_id;
-        (
-            self.oracle_account_id.clone(),
-            self.oracle_provider_id.clone(),
-        )
+        self.oracle_mode = OracleMode::Oracle(FluxSource {
+            oracle_account_id: oracle_account_id.clone(),
+            provider_id: oracle_provider_id.clone(),
+        });
+        (oracle_account_id, oracle_provider_id)
     }
 
-    /// Return the oracle account id and oracle provider id. For more information, refers to FLux oracle docs.
-    pub fn oracle(&self) -> (AccountId, AccountId) {
-        (
-            self.oracle_account_id.clone(),
-            self.oracle_provider_id.clone(),
-        )
+    /// Return the oracle account id and oracle provider id, `None` in fixed price mode. For more
+    /// information, refers to FLux oracle docs.
+    pub fn oracle(&self) -> Option<(AccountId, AccountId)> {
+        match &self.oracle_mode {
+            OracleMode::Oracle(source) => {
+                Some((source.oracle_account_id.clone(), source.provider_id.clone()))
+            }
+            OracleMode::FixedPrice { .. } => None,
+        }
     }
 
     /// Return the `owner_id` of the contract. When contract is initialized `owner_id` is set to
@@ -178,63 +392,172 @@ impl Contract {
     /// Arguments:
     /// - `receivers` - a vec of all receivers' account ID.
     /// - `amount` - a vec of the amount of near sent to each receiver corresponding.
+    /// - `options` - optional settings of the batch, see `DistributeOptions`. With `release_at`
+    /// in the future the NEAR is held by the contract until then, see `release_escrow`. With
+    /// `split` the attached deposit is split across `receivers`, see `SplitMode`.
+    /// Return the escrow id of an escrowed batch, `None` otherwise.
     #[payable]
-    pub fn distribute_near(&mut self, receivers: Vec<AccountId>, amount: Vec<U128>) {
+    pub fn distribute_near(
+        &mut self,
+        receivers: Vec<AccountId>,
+        amount: Vec<U128>,
+        options: Option<DistributeOptions>,
+    ) -> Option<u64> {
+        self.internal_distribute_near(receivers, amount, options.unwrap_or_default())
+    }
+
+    /// Transfer the attached deposit from `env::predecessor_account_id` to `receivers`, see `distribute_near`.
+    fn internal_distribute_near(
+        &mut self,
+        receivers: Vec<AccountId>,
+        amount: Vec<U128>,
+        options: DistributeOptions,
+    ) -> Option<u64> {
+        self.assert_not_paused(Feature::DistributeNear);
+        let mut deposit = env::attached_deposit();
+        let wrap_storage_deposit = if options.wrap_near {
+            assert!(
+                options.release_at.is_none(),
+                "release_at cannot be combined with wrap_near"
+            );
+            self.wrap_storage_deposit * receivers.len() as u128
+        } else {
+            0
+        };
+        assert!(deposit >= wrap_storage_deposit, "Not enough Near attached");
+        deposit -= wrap_storage_deposit;
+        let amount = match options.split {
+            Some(split) => {
+                let shares = split.split(deposit, receivers.len(), &amount);
+                let remainder = deposit - shares.iter().map(|x| x.0).sum::<u128>();
+                if remainder > 0 {
+                    self.refund_near(&env::predecessor_account_id(), remainder);
+                    deposit -= remainder;
+                }
+                shares
+            }
+            None => amount,
+        };
+        let (receivers, amount) = options.duplicates.unwrap_or(DuplicateMode::Allow).apply(
+            &env::predecessor_account_id(),
+            receivers,
+            amount,
+        );
         let total_amount: Balance = amount.iter().map(|x| x.0).sum();
         assert_eq!(receivers.len(), amount.len(), "invalid parameters");
-        assert_eq!(
-            total_amount,
-            env::attached_deposit(),
-            "Not enough Near attached"
-        );
-        assert!(
-            self.get_account_quota(&env::signer_account_id()) >= receivers.len() as u128,
-            "Not enough quota for user"
+        assert_eq!(total_amount, deposit, "Not enough Near attached");
+        let account_policy = options
+            .account_policy
+            .unwrap_or(AccountPolicy::AllowImplicit);
+        account_policy.check(&receivers);
+        if account_policy == AccountPolicy::RejectMissingNamed {
+            assert!(
+                !options.wrap_near && options.release_at.is_none(),
+                "reject_missing_named cannot be combined with wrap_near or release_at"
+            );
+        }
+        let charge = self.charge_quota(
+            options.quota_owner_id,
+            env::predecessor_account_id(),
+            receivers.len() as u128,
         );
-        let mut transfer_promise = Promise::new(receivers[0].clone()).transfer(amount[0].0);
-        for i in 1..receivers.len() {
-            transfer_promise =
-                transfer_promise.and(Promise::new(receivers[i].clone()).transfer(amount[i].0));
+
+        let distribute_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::DistributeNear(DistributeNearLog {
+                sender_id: charge.spender_id.to_string(),
+                receivers: receivers.len() as u64,
+                total_amount: total_amount.to_string(),
+                account_policy: account_policy.as_str().to_string(),
+            }),
+        };
+        env::log_str(&distribute_log.to_string());
+        if options.wrap_near {
+            self.distribute_wnear(receivers, amount, charge, wrap_storage_deposit);
+            return None;
+        }
+        if let Some(release_at) = options.release_at {
+            if env::block_timestamp() < release_at.0 {
+                return Some(self.create_escrow(None, receivers, amount, release_at, charge));
+            }
+        }
+        if account_policy == AccountPolicy::RejectMissingNamed {
+            if let Some(checks) = named_account_checks(&receivers) {
+                let gas = GAS_FOR_CHECK_ACCOUNTS_CALLBACK
+                    + GAS_FOR_CHECKED_TRANSFER * receivers.len() as u64;
+                checks.then(ext_self::callback_check_named_accounts(
+                    receivers,
+                    amount,
+                    charge,
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    gas,
+                ));
+                return None;
+            }
         }
-        self.decrease_account_quota(&env::signer_account_id(), receivers.len() as u128);
-        transfer_promise.then(ext_self::callback_transfer_near(
+        near_transfers(&receivers, &amount).then(ext_self::callback_transfer_near(
             receivers,
             amount,
+            charge,
             env::current_account_id(),
             NO_DEPOSIT,
             GAS_FOR_TRANSFER_NEAR_CALLBACK,
         ));
+        None
     }
 
+    /// Send a `reject_missing_named` batch once its named receivers are checked. If any of them
+    /// does not exist nothing is sent, the NEAR and quota are refunded and the missing receivers
+    /// are reported.
     #[private]
-    pub fn callback_transfer_near(&mut self, receivers: Vec<AccountId>, amount: Vec<U128>) {
-        assert_eq!(env::promise_results_count(), receivers.len() as u64);
-        let mut refund: U128 = 0.into();
-        let mut total_failed_transfer: u128 = 0;
-        for i in 0..receivers.len() {
-            match env::promise_result(i as u64) {
-                PromiseResult::NotReady => {
-                    log!(" Transfer not ready ");
-                }
-                PromiseResult::Failed => {
-                    refund = (refund.0 + amount[i].0).into();
-                    total_failed_transfer += 1;
-                }
-                PromiseResult::Successful(_) => {}
-            };
+    pub fn callback_check_named_accounts(
+        &mut self,
+        receivers: Vec<AccountId>,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+    ) {
+        assert_eq!(receivers.len(), amount.len());
+        let missing = missing_named_accounts(&receivers);
+        if missing.is_empty() {
+            near_transfers(&receivers, &amount).then(ext_self::callback_transfer_near(
+                receivers,
+                amount,
+                charge,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_TRANSFER_NEAR_CALLBACK,
+            ));
+            return;
         }
+        let total_amount: Balance = amount.iter().map(|x| x.0).sum();
+        let reject_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::RejectMissingAccounts(RejectMissingAccountsLog {
+                sender_id: charge.spender_id.to_string(),
+                missing_receivers: missing.iter().map(|id| id.to_string()).collect(),
+                refund_amount: total_amount.to_string(),
+            }),
+        };
+        env::log_str(&reject_log.to_string());
+        self.refund_near(&charge.spender_id, total_amount);
+        self.refund_quota(&charge, receivers.len() as u128);
+    }
+
+    #[private]
+    pub fn callback_transfer_near(
+        &mut self,
+        receivers: Vec<AccountId>,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+    ) {
+        assert_eq!(receivers.len(), amount.len());
+        let (refund, total_failed_transfer) = failed_transfers(&amount);
         if total_failed_transfer > 0 {
-            let refund_log: EventLog = EventLog {
-                standard: EVENT_STANDARD_NAME.to_string(),
-                version: EVENT_VERSION.to_string(),
-                event: EventLogVariant::RefundNear(RefundNearLog {
-                    refund_amount: refund.0.to_string(),
-                    user_id: env::signer_account_id().to_string(),
-                }),
-            };
-            env::log_str(&refund_log.to_string());
-            Promise::new(env::signer_account_id()).transfer(refund.0);
-            self.increase_account_quota(&env::signer_account_id(), total_failed_transfer);
+            self.refund_near(&charge.spender_id, refund);
+            self.refund_quota(&charge, total_failed_transfer);
         }
     }
 
@@ -244,11 +567,21 @@ impl Contract {
     ///
     /// Requirements:
     /// - `msg` argument must follow this format `"msg": "bob.testnet:20#alice.testnet:50"`. This means
-    /// `bob.testnet` receive 20 tokens and `alice.testnet` receive 50 tokens.
+    /// `bob.testnet` receive 20 tokens and `alice.testnet` receive 50 tokens. It can also be a JSON
+    /// `FtDistributeMsg` with the same `receivers`, `amount` and `options` as `distribute_near`,
+    /// or with the `list_id` of a finalized recipient list, distributed in chunks of `limit` entries
+    /// from `from_index` like `distribute_near_from_list`. A `schedule_id` funds that schedule.
+    /// With `vesting` terms the tokens are held and vest to the receivers, see `withdraw_vested`.
+    /// With `options.release_at` in the future the tokens are held until then, see `release_escrow`.
+    /// With `options.split` the received amount is split, see `SplitMode`. With `swap` the tokens
+    /// are swapped on the configured DEX and `amount` is sent in the output token, see `SwapStep`.
     /// - Both `bob` and `alice` must register storage for token contract in advance.
     /// - `sender_id` balance must be greater or equal to the total amount sent to each receiver.
     /// - `sender_id` must pay service fee to get enough quota to transfer near.
     ///
+    /// The `sender_id` reported by the token contract is the account of record, so accounts
+    /// that are contracts (DAOs, multisigs) can distribute tokens too.
+    ///
     /// Arguments:
     /// - `sender_id`: the account id of sender.
     /// - `amount`: the amount of token that sender transfer to this contract by calling `ft_transfer_call`.
@@ -259,49 +592,107 @@ impl Contract {
         amount: U128,
         msg: String,
     ) -> PromiseOrValue<U128> {
-        if env::signer_account_id() != sender_id {
-            env::panic_str("sender_id is not signer");
+        if self.paused.get(Feature::DistributeFt) {
+            log!(
+                "{} is paused, refunding {}",
+                Feature::DistributeFt.as_str(),
+                amount.0
+            );
+            return PromiseOrValue::Value(amount);
         }
-        let receivers_info: Vec<&str> = msg.split("#").collect();
-        let mut account_id_arr: Vec<AccountId> = Vec::new();
-        let mut amount_arr: Vec<U128> = Vec::new();
-        for (_, receiver_info) in receivers_info.iter().enumerate() {
-            let mut info = receiver_info.split(":");
-            let account_id = info.next().unwrap();
-            let transfer_amount: &str = info.next().unwrap();
-            account_id_arr.push(AccountId::new_unchecked(String::from(account_id)));
-            amount_arr.push(transfer_amount.parse::<u128>().unwrap().into());
+        let FtDistributeMsg {
+            receivers,
+            amount: amounts,
+            list_id,
+            from_index,
+            limit,
+            schedule_id,
+            vesting,
+            swap,
+            options,
+        } = FtDistributeMsg::parse(&msg).unwrap_or_else(|err| env::panic_str(&err));
+        if let Some(schedule_id) = schedule_id {
+            self.fund_token_schedule(schedule_id, amount.0);
+            return PromiseOrValue::Value(0.into());
         }
-        let total_amount: U128 = amount_arr.iter().map(|x| x.0).sum::<u128>().into();
-        assert_eq!(total_amount, amount, "Not enough amount of token sent");
-        assert!(
-            self.get_account_quota(&sender_id) >= account_id_arr.len() as u128,
-            "Not enough quota for user"
-        );
-
-        let mut ft_transfer_promise = ext_ft::ft_transfer(
-            account_id_arr[0].clone(),
-            amount_arr[0].clone(),
-            Some(String::from("")),
-            env::predecessor_account_id(), // contract account id
-            1,                             // yocto NEAR to attach
-            GAS_FOR_FT_TRANSFER,
-        );
-        for i in 1..amount_arr.len() {
-            ft_transfer_promise = ft_transfer_promise.and(ext_ft::ft_transfer(
-                account_id_arr[i].clone(),
-                amount_arr[i].clone(),
-                Some(String::from("")),
-                env::predecessor_account_id(), // contract account id
-                1,                             // yocto NEAR to attach
-                GAS_FOR_FT_TRANSFER,
-            ));
+        let (account_id_arr, amount_arr) = match list_id {
+            Some(list_id) => {
+                self.take_list_chunk(list_id, &sender_id, from_index, limit.unwrap_or(u64::MAX))
+            }
+            None => (receivers, amounts),
+        };
+        let amount_arr = match options.split {
+            Some(split) => split.split(amount.0, account_id_arr.len(), &amount_arr),
+            None => amount_arr,
+        };
+        let (account_id_arr, amount_arr) = options
+            .duplicates
+            .unwrap_or(DuplicateMode::Allow)
+            .apply(&sender_id, account_id_arr, amount_arr);
+        assert_eq!(account_id_arr.len(), amount_arr.len(), "invalid parameters");
+        if let Some(step) = swap {
+            assert!(
+                vesting.is_none() && options.release_at.is_none() && options.split.is_none(),
+                "swap cannot be combined with vesting, release_at or split"
+            );
+            let charge = self.charge_quota(
+                options.quota_owner_id,
+                sender_id,
+                account_id_arr.len() as u128,
+            );
+            return self.swap_and_distribute(PendingSwap {
+                dex_id: self.dex_id.clone().expect("Swaps are not enabled"),
+                token_in: env::predecessor_account_id(),
+                amount_in: amount,
+                step,
+                receivers: account_id_arr,
+                amount: amount_arr,
+                charge,
+            });
+        }
+        let total_amount: Balance = amount_arr.iter().map(|x| x.0).sum();
+        // the remainder of a split is returned to the sender
+        let unused_amount = match options.split {
+            Some(_) => amount.0 - total_amount,
+            None => {
+                assert_eq!(total_amount, amount.0, "Not enough amount of token sent");
+                0
+            }
+        };
+        let charge = self.charge_quota(
+            options.quota_owner_id,
+            sender_id.clone(),
+            account_id_arr.len() as u128,
+        );
+        if let Some(terms) = vesting {
+            assert!(
+                options.release_at.is_none(),
+                "release_at cannot be combined with vesting"
+            );
+            self.create_vestings(&sender_id, account_id_arr, amount_arr, terms);
+            return PromiseOrValue::Value(unused_amount.into());
+        }
+        if let Some(release_at) = options.release_at {
+            if env::block_timestamp() < release_at.0 {
+                let token_id = env::predecessor_account_id();
+                self.create_escrow(
+                    Some(token_id),
+                    account_id_arr,
+                    amount_arr,
+                    release_at,
+                    charge,
+                );
+                return PromiseOrValue::Value(unused_amount.into());
+            }
         }
-        self.decrease_account_quota(&env::signer_account_id(), account_id_arr.len() as u128);
-        ft_transfer_promise
+
+        // the predecessor is the token contract
+        ft_transfers(&env::predecessor_account_id(), &account_id_arr, &amount_arr)
             .then(ext_self::callback_ft_transfer(
                 account_id_arr,
                 amount_arr,
+                charge,
+                unused_amount.into(),
                 env::current_account_id(),
                 NO_DEPOSIT,
                 GAS_FOR_FT_TRANSFER_CALLBACK,
@@ -310,26 +701,33 @@ impl Contract {
     }
 
     #[private]
-    pub fn callback_ft_transfer(&mut self, account_ids: Vec<AccountId>, amount: Vec<U128>) -> U128 {
-        assert_eq!(env::promise_results_count(), account_ids.len() as u64);
-        let mut refund: U128 = 0.into();
-        let mut total_failed_transfer: u128 = 0;
-        for i in 0..account_ids.len() {
-            match env::promise_result(i as u64) {
-                PromiseResult::NotReady => {
-                    log!(" Transfer not ready ");
-                }
-                PromiseResult::Failed => {
-                    refund = (refund.0 + amount[i].0).into();
-                    total_failed_transfer += 1;
-                }
-                PromiseResult::Successful(_) => {}
-            };
-        }
+    pub fn callback_ft_transfer(
+        &mut self,
+        account_ids: Vec<AccountId>,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+        unused_amount: U128,
+    ) -> U128 {
+        assert_eq!(account_ids.len(), amount.len());
+        let (refund, total_failed_transfer) = failed_transfers(&amount);
+        self.refund_quota(&charge, total_failed_transfer);
+        (refund + unused_amount.0).into()
+    }
+
+    /// Refund failed transfers of funds held by the contract, `token_id` or NEAR when `None`,
+    /// to the sender of the batch.
+    #[private]
+    pub fn callback_transfer_funds(
+        &mut self,
+        token_id: Option<AccountId>,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+    ) {
+        let (refund, total_failed_transfer) = failed_transfers(&amount);
         if total_failed_transfer > 0 {
-            self.increase_account_quota(&env::signer_account_id(), total_failed_transfer);
+            self.refund_funds(token_id, &charge.spender_id, refund);
+            self.refund_quota(&charge, total_failed_transfer);
         }
-        refund
     }
 
     /// A payable method that helps pay token storage fee for multiple accounts.
@@ -350,6 +748,7 @@ impl Contract {
         account_ids: Vec<AccountId>,
         min_fee: U128,
     ) {
+        self.assert_not_paused(Feature::StorageDeposit);
         assert_eq!(
             min_fee.0 * account_ids.len() as u128,
             env::attached_deposit(),
@@ -372,6 +771,7 @@ impl Contract {
             ));
         }
         storage_deposit_promise.then(ext_self::callback_storage_deposit(
+            env::predecessor_account_id(),
             account_ids,
             min_fee,
             env::current_account_id(),
@@ -381,7 +781,12 @@ impl Contract {
     }
 
     #[private]
-    pub fn callback_storage_deposit(&self, account_ids: Vec<AccountId>, min_fee: U128) {
+    pub fn callback_storage_deposit(
+        &self,
+        sender_id: AccountId,
+        account_ids: Vec<AccountId>,
+        min_fee: U128,
+    ) {
         assert_eq!(env::promise_results_count(), account_ids.len() as u64);
         let mut refund: U128 = 0.into();
         for i in 0..account_ids.len() {
@@ -397,97 +802,179 @@ impl Contract {
             };
         }
         if refund.0 > 0 {
+            self.refund_near(&sender_id, refund.0);
+        }
+    }
+
+    /// A payable method to pay service fee. This method will increase
+    /// the number of user quota to transfer near and tokens. The fee per address
+    /// is reduced by the fee discount of the caller, fee-exempt accounts cannot pay. The storage
+    /// balance of the caller pays for the quota records, a caller that is not registered with
+    /// `storage_deposit` or lacks storage balance pays it from the attached deposit first.
+    ///
+    /// Arguments:
+    /// - `estimated_fee` is the amount near equals 0.05 USD. It is ignored in fixed price mode,
+    ///   where the discounted fixed price is charged per address without querying the oracle and
+    ///   the rest of the deposit is refunded.
+    /// - `referrer_id` is the optional partner account credited with the referral share of the fee.
+    #[payable]
+    pub fn pay_service_fee(&mut self, estimated_fee: U128, referrer_id: Option<AccountId>) {
+        self.assert_not_paused(Feature::PayFee);
+        let account_id = env::predecessor_account_id();
+        assert!(
+            !self.is_fee_exempt(&account_id),
+            "Account is exempt from fees"
+        );
+        let amount = self.take_payment_storage(&account_id, env::attached_deposit());
+        if let OracleMode::FixedPrice { yocto_per_address } = self.oracle_mode {
+            let fee = self.discounted_fee(&account_id, yocto_per_address.0);
+            assert!(
+                fee > 0 && amount >= fee,
+                "Attached deposit does not cover the fixed price of one address"
+            );
+            self.credit_service_fee(account_id, amount, referrer_id, fee);
+            return;
+        }
+        self.query_near_price().then(ext_self::callback_get_entry(
+            account_id,
+            estimated_fee,
+            amount,
+            referrer_id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.price_callback_gas(GAS_FOR_GET_ENTRY_CALLBACK),
+        ));
+    }
+
+    #[private]
+    pub fn callback_get_entry(
+        &mut self,
+        account_id: AccountId,
+        estimated_fee: U128,
+        amount: Balance,
+        referrer_id: Option<AccountId>,
+    ) {
+        let res = match self.near_price_result() {
+            Some(res) => res,
+            // the sources failed or disagree, no price to credit quota at
+            None => return self.refund_near(&account_id, amount),
+        };
+        let oracle_fee = self.discounted_fee(
+            &account_id,
+            res.usd_to_yocto(
+                FEE_USD_PER_ADDRESS_VALUE,
+                FEE_USD_PER_ADDRESS_DECIMAL_OFFSET,
+            ),
+        );
+        self.internal_pay_service_fee(account_id, estimated_fee, amount, referrer_id, oracle_fee);
+    }
+
+    /// Credit quota for `amount` paid at `estimated_fee` per address, or refund it if
+    /// `estimated_fee` deviates more than 10% from `oracle_fee`.
+    fn internal_pay_service_fee(
+        &mut self,
+        account_id: AccountId,
+        estimated_fee: U128,
+        amount: Balance,
+        referrer_id: Option<AccountId>,
+        oracle_fee: Balance,
+    ) {
+        let oracle_fee: U128 = oracle_fee.into();
+        log!(
+            "diff: {}, rate: {}, oracle_fee: {}, estimated_fee: {}",
+            oracle_fee.0.abs_diff(estimated_fee.0),
+            oracle_fee.0 / 10u128,
+            oracle_fee.0,
+            estimated_fee.0
+        );
+        if oracle_fee.0.abs_diff(estimated_fee.0) > oracle_fee.0 / 10u128 {
             let refund_log: EventLog = EventLog {
                 standard: EVENT_STANDARD_NAME.to_string(),
                 version: EVENT_VERSION.to_string(),
                 event: EventLogVariant::RefundNear(RefundNearLog {
-                    refund_amount: refund.0.to_string(),
-                    user_id: env::signer_account_id().to_string(),
+                    refund_amount: amount.to_string(),
+                    user_id: account_id.to_string(),
                 }),
             };
             env::log_str(&refund_log.to_string());
-            Promise::new(env::signer_account_id()).transfer(refund.0);
+            Promise::new(account_id).transfer(amount);
+        } else {
+            self.credit_service_fee(account_id, amount, referrer_id, estimated_fee.0);
         }
     }
 
-    /// A payable method to pay service fee. This method will increase
-    /// the number of user quota to transfer near and tokens.
-    #[payable]
-    pub fn pay_service_fee(&mut self, estimated_fee: U128) {
-        Promise::new(AccountId::new_unchecked(self.oracle_account_id.to_string()))
-            .function_call(
-                "get_entry".to_string(),
-                serde_json::to_vec(&json!({
-                    "pair": "NEAR/USD".to_string(),
-                    "provider": self.oracle_provider_id.to_string()
-                }))
-                .unwrap(),
-                NO_DEPOSIT,
-                Gas(5_000_000_000_000),
-            )
-            .then(ext_self::callback_get_entry(
-                estimated_fee,
-                env::attached_deposit(),
-                env::current_account_id(),
-                NO_DEPOSIT,
-                GAS_FOR_STORAGE_DEPOSIT_CALLBACK,
-            ));
+    /// Credit `account_id` with the quota `amount` pays for at `fee_per_address` and refund the
+    /// rest of `amount`.
+    fn credit_service_fee(
+        &mut self,
+        account_id: AccountId,
+        amount: Balance,
+        referrer_id: Option<AccountId>,
+        fee_per_address: Balance,
+    ) {
+        let initial_storage_usage = env::storage_usage();
+        let redundant_coin = amount % fee_per_address;
+        if redundant_coin != 0 {
+            Promise::new(account_id.clone()).transfer(redundant_coin);
+        }
+
+        let num_addr: u128 = amount / fee_per_address;
+        let current_quota: u128 = self.get_account_quota(&account_id);
+        let pay_fee_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::PayFee(PayFeeLog {
+                amount: amount.to_string(),
+                refund: redundant_coin.to_string(),
+                user_id: account_id.to_string(),
+                old_quota: current_quota.to_string(),
+                new_quota: (current_quota + num_addr).to_string(),
+            }),
+        };
+        self.increase_account_quota(&account_id, num_addr);
+        let fee = amount - redundant_coin;
+        let referral_share = self.credit_referral(referrer_id, &account_id, fee);
+        self.collected_fees += fee - referral_share;
+        // redemptions refund only what was kept, the referral share stays with the referrer
+        if num_addr > 0 {
+            self.add_quota_lot(&account_id, num_addr, (fee - referral_share) / num_addr);
+        }
+        env::log_str(&pay_fee_log.to_string());
+        self.record_storage(&account_id, initial_storage_usage);
     }
 
-    #[private]
-    pub fn callback_get_entry(&mut self, estimated_fee: U128, amount: Balance) {
-        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
-        match env::promise_result(0) {
-            PromiseResult::NotReady => unreachable!(),
-            PromiseResult::Failed => {}
-            PromiseResult::Successful(result) => {
-                let res = near_sdk::serde_json::from_slice::<OracleEntry>(&result).unwrap();
-                let oracle_fee: U128 = (ONE_NEAR
-                    * FEE_USD_PER_ADDRESS_VALUE
-                    * 10u128.pow(res.decimals - FEE_USD_PER_ADDRESS_DECIMAL_OFFSET)
-                    / res.price.0)
-                    .into();
-                log!(
-                    "diff: {}, rate: {}, oracle_fee: {}, estimated_fee: {}",
-                    oracle_fee.0.abs_diff(estimated_fee.0),
-                    oracle_fee.0 / 10u128,
-                    oracle_fee.0,
-                    estimated_fee.0
-                );
-                if oracle_fee.0.abs_diff(estimated_fee.0) > oracle_fee.0 / 10u128 {
-                    let refund_log: EventLog = EventLog {
-                        standard: EVENT_STANDARD_NAME.to_string(),
-                        version: EVENT_VERSION.to_string(),
-                        event: EventLogVariant::RefundNear(RefundNearLog {
-                            refund_amount: amount.to_string(),
-                            user_id: env::signer_account_id().to_string(),
-                        }),
-                    };
-                    env::log_str(&refund_log.to_string());
-                    Promise::new(env::signer_account_id()).transfer(amount);
-                } else {
-                    let redundant_coin = amount % estimated_fee.0;
-                    if redundant_coin != 0 {
-                        Promise::new(env::signer_account_id()).transfer(redundant_coin);
-                    }
-
-                    let num_addr: u128 = amount / estimated_fee.0;
-                    let current_quota: u128 = self.get_account_quota(&env::signer_account_id());
-                    let pay_fee_log: EventLog = EventLog {
-                        standard: EVENT_STANDARD_NAME.to_string(),
-                        version: EVENT_VERSION.to_string(),
-                        event: EventLogVariant::PayFee(PayFeeLog {
-                            amount: amount.to_string(),
-                            refund: redundant_coin.to_string(),
-                            user_id: env::signer_account_id().to_string(),
-                            old_quota: current_quota.to_string(),
-                            new_quota: (current_quota + num_addr).to_string(),
-                        }),
-                    };
-                    self.increase_account_quota(&env::signer_account_id(), num_addr);
-                    env::log_str(&pay_fee_log.to_string());
-                }
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "only contract owner can call this method"
+        );
+    }
+
+    fn refund_near(&self, account_id: &AccountId, amount: Balance) {
+        let refund_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::RefundNear(RefundNearLog {
+                refund_amount: amount.to_string(),
+                user_id: account_id.to_string(),
+            }),
+        };
+        env::log_str(&refund_log.to_string());
+        Promise::new(account_id.clone()).transfer(amount);
+    }
+
+    /// Send `amount` of `token_id`, or of NEAR when `None`, held by the contract back to `account_id`.
+    /// Tokens that cannot be sent stay claimable, see `claim_token_refund`.
+    fn refund_funds(&self, token_id: Option<AccountId>, account_id: &AccountId, amount: Balance) {
+        if amount == 0 {
+            return;
+        }
+        match token_id {
+            Some(token_id) => {
+                self.refund_token(token_id, account_id, amount);
             }
+            None => self.refund_near(account_id, amount),
         }
     }
 
@@ -499,6 +986,7 @@ impl Contract {
     fn decrease_account_quota(&mut self, account_id: &AccountId, num: u128) {
         let value = self.get_account_quota(account_id) - num;
         self.balances.insert(&account_id, &value);
+        self.trim_quota_lots(account_id, value);
     }
 
     /// Return the number of quota for `account_id`. This quota is the total number of account that user can
@@ -511,14 +999,26 @@ impl Contract {
 #[cfg(all(test, not(target_arch = "wasm32")))]
 #[cfg(test)]
 mod tests {
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{accounts, get_created_receipts, get_logs, VMContextBuilder};
     // use near_sdk::MockedBlockchain;
-    use near_sdk::testing_env;
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig};
 
     use super::*;
 
     // const TOTAL_SUPPLY: Balance = 1_000_000_000_000_000;
 
+    fn register_storage(contract: &mut Contract, account_id: AccountId) {
+        testing_env!(get_context(account_id).attached_deposit(ONE_NEAR).build());
+        contract.storage_deposit(None, None);
+    }
+
+    /// Return the storage balance of `account_id` used by its state.
+    fn storage_used(contract: &Contract, account_id: AccountId) -> Balance {
+        let balance = contract.storage_balance_of(account_id).unwrap();
+        balance.total.0 - balance.available.0
+    }
+
     fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
         let mut builder = VMContextBuilder::new();
         builder
@@ -540,60 +1040,1806 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "The contract is not initialized")]
-    fn test_default() {
+    fn test_withdraw_fees() {
         let context = get_context(accounts(1));
         testing_env!(context.build());
-        let _contract = Contract::default();
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        contract.collected_fees = 10;
+        contract.set_treasury(Some(accounts(2)));
+        testing_env!(get_context(accounts(2)).build());
+        contract.withdraw_fees(4.into(), accounts(2));
+        let revenue = contract.fee_revenue();
+        assert_eq!(revenue.collected_fees.0, 6);
+        assert_eq!(revenue.withdrawn_fees.0, 4);
     }
 
     #[test]
-    fn test_increase_account_quota() {
-        let mut context = get_context(accounts(1));
+    #[should_panic(expected = "Not enough collected fees")]
+    fn test_withdraw_fees_over_collected() {
+        let context = get_context(accounts(1));
         testing_env!(context.build());
-        let mut contract = Contract::new(
-            AccountId::new_unchecked("id".to_string()),
-            AccountId::new_unchecked("id".to_string()),
-        ); // testing_env!(context.is_view(true).build());
-        let service_fee: U128 = 1u128.into();
-        testing_env!(context
-            .storage_usage(env::storage_usage())
-            .attached_deposit(service_fee.0)
-            .predecessor_account_id(accounts(2))
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        contract.collected_fees = 10;
+        contract.withdraw_fees(11.into(), accounts(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "only contract owner or treasury can withdraw fees")]
+    fn test_withdraw_fees_requires_owner_or_treasury() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        contract.collected_fees = 10;
+        contract.set_treasury(Some(accounts(2)));
+        testing_env!(get_context(accounts(5)).build());
+        contract.withdraw_fees(1.into(), accounts(5));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough balance to cover storage and held NEAR")]
+    fn test_withdraw_fees_keeps_held_near() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        // the whole balance is the storage deposit of accounts(2) and the collected fees
+        testing_env!(get_context(accounts(2))
+            .account_balance(10 * ONE_NEAR)
+            .attached_deposit(9 * ONE_NEAR)
             .build());
-        // contract.pay_service_fee();
-        testing_env!(context
-            .storage_usage(env::storage_usage())
-            .attached_deposit(service_fee.0)
-            .predecessor_account_id(accounts(2))
+        contract.storage_deposit(None, None);
+        assert_eq!(contract.fee_revenue().held_near.0, 9 * ONE_NEAR);
+        contract.collected_fees = ONE_NEAR;
+        testing_env!(get_context(accounts(1))
+            .account_balance(10 * ONE_NEAR)
             .build());
-        contract.increase_account_quota(&accounts(2), 1);
-        testing_env!(context.is_view(true).build());
-        assert_eq!(contract.get_account_quota(&accounts(2)), 2);
+        contract.withdraw_fees(ONE_NEAR.into(), accounts(1));
     }
 
     #[test]
-    fn test_decrease_account_quota() {
-        let mut context = get_context(accounts(1));
+    fn test_withdraw_fees_restored_on_failed_transfer() {
+        let context = get_context(accounts(1));
         testing_env!(context.build());
-        let mut contract = Contract::new(
-            AccountId::new_unchecked("id".to_string()),
-            AccountId::new_unchecked("id".to_string()),
-        ); // testing_env!(context.is_view(true).build());
-        let service_fee: U128 = 1u128.into();
-        testing_env!(context
-            .storage_usage(env::storage_usage())
-            .attached_deposit(service_fee.0)
-            .predecessor_account_id(accounts(2))
-            .build());
-        // contract.pay_service_fee();
-        testing_env!(context
-            .storage_usage(env::storage_usage())
-            .attached_deposit(service_fee.0)
-            .predecessor_account_id(accounts(2))
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        contract.collected_fees = 10;
+        contract.withdraw_fees(4.into(), accounts(1));
+        testing_env_with_result(accounts(0), PromiseResult::Failed);
+        contract.callback_withdraw_fees(4.into(), accounts(1));
+        let revenue = contract.fee_revenue();
+        assert_eq!(revenue.collected_fees.0, 10);
+        assert_eq!(revenue.withdrawn_fees.0, 0);
+    }
+
+    fn referred_contract(referrer_id: AccountId) -> Contract {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let price = ONE_NEAR / 100;
+        let mut contract = Contract::new_with_fixed_price(price.into());
+        contract.set_referral_fee(2_000);
+        register_storage(&mut contract, accounts(2));
+        testing_env!(get_context(accounts(2)).attached_deposit(3 * price).build());
+        contract.pay_service_fee(price.into(), Some(referrer_id));
+        contract
+    }
+
+    #[test]
+    fn test_referral_fee_split() {
+        let contract = referred_contract(accounts(3));
+        let fee = 3 * ONE_NEAR / 100;
+        let stats = contract.referral_stats(accounts(3));
+        assert_eq!(stats.claimable.0, fee / 5);
+        assert_eq!(stats.total_earned.0, fee / 5);
+        assert_eq!(stats.referred_payments, 1);
+        assert_eq!(contract.collected_fees, fee - fee / 5);
+    }
+
+    #[test]
+    fn test_self_referral_ignored() {
+        let contract = referred_contract(accounts(2));
+        assert_eq!(contract.referral_stats(accounts(2)).claimable.0, 0);
+        assert_eq!(contract.collected_fees, 3 * ONE_NEAR / 100);
+    }
+
+    #[test]
+    fn test_claim_referral_rewards() {
+        let mut contract = referred_contract(accounts(3));
+        let reward = contract.referral_stats(accounts(3)).claimable.0;
+        let held_near = contract.held_near;
+        testing_env!(get_context(accounts(3)).build());
+        contract.claim_referral_rewards();
+        assert_eq!(contract.referral_stats(accounts(3)).claimable.0, 0);
+        assert_eq!(contract.held_near, held_near - reward);
+        // a failed transfer makes the reward claimable again
+        testing_env_with_result(accounts(0), PromiseResult::Failed);
+        contract.callback_claim_referral_rewards(accounts(3), reward.into());
+        let stats = contract.referral_stats(accounts(3));
+        assert_eq!(stats.claimable.0, reward);
+        assert_eq!(stats.total_earned.0, reward);
+        assert_eq!(contract.held_near, held_near);
+    }
+
+    #[test]
+    #[should_panic(expected = "No referral rewards to claim")]
+    fn test_claim_referral_rewards_without_balance() {
+        let mut contract = referred_contract(accounts(3));
+        testing_env!(get_context(accounts(4)).build());
+        contract.claim_referral_rewards();
+    }
+
+    #[test]
+    fn test_pay_service_fee_fixed_price() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let price = ONE_NEAR / 100;
+        let mut contract = Contract::new_with_fixed_price(price.into());
+        assert_eq!(contract.oracle(), None);
+        register_storage(&mut contract, accounts(2));
+        testing_env!(get_context(accounts(2)).attached_deposit(3 * price).build());
+        contract.pay_service_fee(price.into(), None);
+        assert_eq!(contract.get_account_quota(&accounts(2)), 3);
+        assert_eq!(contract.collected_fees, 3 * price);
+    }
+
+    #[test]
+    fn test_pay_service_fee_fixed_price_ignores_estimate() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let price = ONE_NEAR / 100;
+        let mut contract = Contract::new_with_fixed_price(price.into());
+        testing_env!(get_context(accounts(1)).build());
+        contract.set_fee_discount(accounts(2), 50);
+        register_storage(&mut contract, accounts(2));
+        // the estimate is far off the fixed price, only the discounted fixed price is charged
+        testing_env!(get_context(accounts(2))
+            .attached_deposit(3 * price / 2 + 7)
             .build());
-        contract.decrease_account_quota(&accounts(2), 1);
-        testing_env!(context.is_view(true).build());
-        assert_eq!(contract.get_account_quota(&accounts(2)), 0);
+        contract.pay_service_fee((10 * price).into(), None);
+        assert_eq!(contract.get_account_quota(&accounts(2)), 3);
+        assert_eq!(contract.collected_fees, 3 * price / 2);
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.contains("\"refund\":\"7\"")));
     }
-}
\ No newline at end of file
+
+    #[test]
+    #[should_panic(expected = "cannot be subscribed to in fixed price mode")]
+    fn test_subscribe_fixed_price() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new_with_fixed_price((ONE_NEAR / 100).into());
+        testing_env!(get_context(accounts(1)).build());
+        let plan_id = contract.add_subscription_plan(500.into(), 1_000.into(), None);
+        register_storage(&mut contract, accounts(2));
+        testing_env!(get_context(accounts(2)).attached_deposit(ONE_NEAR).build());
+        contract.subscribe(plan_id, None);
+    }
+
+    #[test]
+    fn test_redeem_quota_fixed_price() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let price = ONE_NEAR / 100;
+        let mut contract = Contract::new_with_fixed_price(price.into());
+        register_storage(&mut contract, accounts(2));
+        testing_env!(get_context(accounts(2)).attached_deposit(3 * price).build());
+        contract.pay_service_fee(price.into(), None);
+        testing_env!(get_context(accounts(2)).build());
+        contract.redeem_quota(2);
+        assert_eq!(contract.get_account_quota(&accounts(2)), 1);
+        assert_eq!(contract.redeemable_quota(accounts(2)), 1);
+        assert_eq!(contract.collected_fees, price);
+        assert!(get_logs().iter().any(|log| log.contains("redeem_quota")));
+    }
+
+    #[test]
+    fn test_redeem_quota_excludes_referral_share() {
+        let mut contract = referred_contract(accounts(3));
+        let price = ONE_NEAR / 100;
+        let referral_share = 3 * price / 5;
+        assert_eq!(contract.collected_fees, 3 * price - referral_share);
+        assert_eq!(
+            contract.quota_lots_of(accounts(2))[0].price_per_address.0,
+            price - price / 5
+        );
+
+        testing_env!(get_context(accounts(2)).build());
+        contract.redeem_quota(3);
+        assert_eq!(contract.get_account_quota(&accounts(2)), 0);
+        assert_eq!(contract.collected_fees, 0);
+        assert_eq!(
+            contract.referral_stats(accounts(3)).claimable.0,
+            referral_share
+        );
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.contains(&format!("\"refund\":\"{}\"", 3 * price - referral_share))));
+    }
+
+    #[test]
+    fn test_pay_service_fee_registers_storage() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let price = ONE_NEAR / 100;
+        let mut contract = Contract::new_with_fixed_price(price.into());
+        // registration and the payment reserve are taken from the attached deposit
+        let storage = contract.storage_balance_bounds().min.0 + 300 * env::storage_byte_cost();
+        testing_env!(get_context(accounts(2))
+            .attached_deposit(3 * price + storage)
+            .build());
+        contract.pay_service_fee(price.into(), None);
+        assert_eq!(contract.get_account_quota(&accounts(2)), 3);
+        assert_eq!(contract.collected_fees, 3 * price);
+        let balance = contract.storage_balance_of(accounts(2)).unwrap();
+        assert_eq!(balance.total.0, storage);
+    }
+
+    #[test]
+    fn test_callback_get_entry_refunds_without_price() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        register_storage(&mut contract, accounts(2));
+        testing_env!(
+            get_context(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.callback_get_entry(accounts(2), (ONE_NEAR / 100).into(), ONE_NEAR, None);
+        assert_eq!(contract.get_account_quota(&accounts(2)), 0);
+        assert_eq!(contract.collected_fees, 0);
+        assert!(get_logs().iter().any(|log| log.contains("refund_near")));
+    }
+
+    #[test]
+    #[should_panic(expected = "No oracle is used in fixed price mode")]
+    fn test_query_near_price_fixed_price() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        contract.set_oracle_mode(OracleMode::FixedPrice {
+            yocto_per_address: (ONE_NEAR / 100).into(),
+        });
+        contract.query_near_price();
+    }
+
+    #[test]
+    #[should_panic(expected = "The contract is not initialized")]
+    fn test_default() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let _contract = Contract::default();
+    }
+
+    #[test]
+    fn test_increase_account_quota() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        ); // testing_env!(context.is_view(true).build());
+        let service_fee: U128 = 1u128.into();
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(service_fee.0)
+            .predecessor_account_id(accounts(2))
+            .build());
+        // contract.pay_service_fee();
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(service_fee.0)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.increase_account_quota(&accounts(2), 1);
+        testing_env!(context.is_view(true).build());
+        assert_eq!(contract.get_account_quota(&accounts(2)), 2);
+    }
+
+    #[test]
+    fn test_decrease_account_quota() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        ); // testing_env!(context.is_view(true).build());
+        let service_fee: U128 = 1u128.into();
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(service_fee.0)
+            .predecessor_account_id(accounts(2))
+            .build());
+        // contract.pay_service_fee();
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(service_fee.0)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.decrease_account_quota(&accounts(2), 1);
+        testing_env!(context.is_view(true).build());
+        assert_eq!(contract.get_account_quota(&accounts(2)), 0);
+    }
+
+    #[test]
+    fn test_pause_refunds_ft_on_transfer() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        contract.add_pauser(accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.pause(Feature::DistributeFt);
+        assert!(contract.is_paused(Feature::DistributeFt));
+        assert!(!contract.is_paused(Feature::DistributeNear));
+
+        testing_env!(context
+            .signer_account_id(accounts(3))
+            .predecessor_account_id(accounts(4))
+            .build());
+        let amount: U128 = 100u128.into();
+        match contract.ft_on_transfer(accounts(3), amount, format!("{}:100", accounts(2))) {
+            PromiseOrValue::Value(refund) => assert_eq!(refund.0, amount.0),
+            PromiseOrValue::Promise(_) => panic!("paused transfer must be refunded"),
+        }
+    }
+
+    #[test]
+    fn test_quota_allowance_charge_and_refund() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        register_storage(&mut contract, accounts(1));
+        testing_env!(context.build());
+        contract.increase_account_quota(&accounts(1), 10);
+        contract.approve_quota_spender(accounts(2), 4);
+
+        let charge = contract.charge_quota(Some(accounts(1)), accounts(2), 3);
+        assert_eq!(contract.get_account_quota(&accounts(1)), 7);
+        assert_eq!(contract.quota_allowance(accounts(1), accounts(2)), 1);
+
+        contract.refund_quota(&charge, 2);
+        assert_eq!(contract.get_account_quota(&accounts(1)), 9);
+        assert_eq!(contract.quota_allowance(accounts(1), accounts(2)), 3);
+
+        contract.transfer_quota(accounts(3), 5);
+        assert_eq!(contract.get_account_quota(&accounts(1)), 4);
+        assert_eq!(contract.get_account_quota(&accounts(3)), 5);
+    }
+
+    #[test]
+    fn test_subscription_used_before_quota() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        contract.increase_account_quota(&accounts(1), 10);
+        contract.subscriptions.insert(
+            &accounts(1),
+            &Subscription {
+                plan_id: 0,
+                expires_at: 1_000.into(),
+                address_cap: Some(5),
+                addresses_used: 0,
+            },
+        );
+
+        let charge = contract.charge_quota(None, accounts(1), 7);
+        assert_eq!(charge.from_subscription, 5);
+        assert_eq!(contract.get_account_quota(&accounts(1)), 8);
+
+        contract.refund_quota(&charge, 3);
+        assert_eq!(contract.get_account_quota(&accounts(1)), 10);
+        let subscription = contract.subscription_of(accounts(1)).unwrap();
+        assert_eq!(subscription.addresses_used, 4);
+    }
+
+    #[test]
+    fn test_spent_quota_trims_oldest_lots() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        register_storage(&mut contract, accounts(1));
+        testing_env!(context.build());
+        contract.increase_account_quota(&accounts(1), 12);
+        contract.add_quota_lot(&accounts(1), 5, 10);
+        contract.add_quota_lot(&accounts(1), 5, 20);
+        assert_eq!(contract.redeemable_quota(accounts(1)), 10);
+
+        contract.decrease_account_quota(&accounts(1), 4);
+        let lots = contract.quota_lots_of(accounts(1));
+        assert_eq!(lots.len(), 2);
+        assert_eq!(lots[0].remaining, 3);
+        assert_eq!(lots[0].price_per_address.0, 10);
+        assert_eq!(contract.redeemable_quota(accounts(1)), 8);
+
+        contract.transfer_quota(accounts(2), 5);
+        let lots = contract.quota_lots_of(accounts(1));
+        assert_eq!(lots.len(), 1);
+        assert_eq!(lots[0].remaining, 3);
+        assert_eq!(lots[0].price_per_address.0, 20);
+    }
+
+    #[test]
+    fn test_fee_exempt_account_uses_no_quota() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        contract.set_fee_discount(accounts(2), 100);
+        contract.set_fee_discount(accounts(3), 25);
+        assert_eq!(contract.discounted_fee(&accounts(3), 1_000), 750);
+
+        let charge = contract.charge_quota(None, accounts(2), 50);
+        assert_eq!(charge.from_balance + charge.from_subscription, 0);
+        contract.refund_quota(&charge, 10);
+        assert_eq!(contract.get_account_quota(&accounts(2)), 0);
+    }
+
+    #[test]
+    fn test_ft_on_transfer_charges_sender_not_signer() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        contract.increase_account_quota(&accounts(3), 2);
+
+        // A DAO (accounts(3)) sends tokens through a proposal executed by accounts(2).
+        testing_env!(context
+            .signer_account_id(accounts(2))
+            .predecessor_account_id(accounts(4))
+            .build());
+        contract.ft_on_transfer(accounts(3), 30u128.into(), format!("{}:30", accounts(1)));
+        assert_eq!(contract.get_account_quota(&accounts(3)), 1);
+        assert_eq!(contract.get_account_quota(&accounts(2)), 0);
+    }
+
+    fn two_entry_list(context: &mut VMContextBuilder) -> (Contract, u64) {
+        testing_env!(context.attached_deposit(ONE_NEAR).build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        contract.increase_account_quota(&accounts(3), 3);
+        let list_id = contract.create_list();
+        contract.append_to_list(
+            list_id,
+            vec![ListEntry {
+                account_id: accounts(2),
+                amount: 10u128.into(),
+            }],
+        );
+        contract.append_to_list(
+            list_id,
+            vec![ListEntry {
+                account_id: accounts(4),
+                amount: 20u128.into(),
+            }],
+        );
+        contract.finalize_list(list_id);
+        (contract, list_id)
+    }
+
+    #[test]
+    fn test_distribute_near_from_list() {
+        let mut context = get_context(accounts(1));
+        let (mut contract, list_id) = two_entry_list(&mut context);
+        let list = contract.get_list(list_id).unwrap();
+        assert_eq!(list.len, 2);
+        assert_eq!(list.total_amount.0, 30);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(30)
+            .build());
+        contract.distribute_near_from_list(list_id, 0, 10, None);
+        assert_eq!(contract.get_account_quota(&accounts(3)), 1);
+        assert_eq!(contract.get_list(list_id).unwrap().next_index, 0);
+    }
+
+    #[test]
+    fn test_distribute_near_from_list_in_chunks() {
+        let mut context = get_context(accounts(1));
+        let (mut contract, list_id) = two_entry_list(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(10)
+            .build());
+        contract.distribute_near_from_list(list_id, 0, 1, None);
+        let list = contract.get_list(list_id).unwrap();
+        assert_eq!(list.next_index, 1);
+        assert_eq!(list.distributor_id, Some(accounts(3)));
+
+        testing_env!(context.attached_deposit(20).build());
+        contract.distribute_near_from_list(list_id, 1, 1, None);
+        assert_eq!(contract.get_account_quota(&accounts(3)), 1);
+        let list = contract.get_list(list_id).unwrap();
+        assert_eq!(list.next_index, 0);
+        assert_eq!(list.distributor_id, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The distribution of the caller does not continue at 1")]
+    fn test_distribute_near_from_list_continued_by_distributor_only() {
+        let mut context = get_context(accounts(1));
+        let (mut contract, list_id) = two_entry_list(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(10)
+            .build());
+        contract.distribute_near_from_list(list_id, 0, 1, None);
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(20)
+            .build());
+        contract.distribute_near_from_list(list_id, 1, 1, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "A distribution of the list by danny is in progress")]
+    fn test_distribute_near_from_list_restart_by_other_account() {
+        let mut context = get_context(accounts(1));
+        let (mut contract, list_id) = two_entry_list(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(10)
+            .build());
+        contract.distribute_near_from_list(list_id, 0, 1, None);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        contract.distribute_near_from_list(list_id, 0, 1, None);
+    }
+
+    #[test]
+    fn test_distribute_near_from_list_restart_by_owner() {
+        let mut context = get_context(accounts(1));
+        let (mut contract, list_id) = two_entry_list(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(10)
+            .build());
+        contract.distribute_near_from_list(list_id, 0, 1, None);
+        contract.increase_account_quota(&accounts(1), 1);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.distribute_near_from_list(list_id, 0, 1, None);
+        let list = contract.get_list(list_id).unwrap();
+        assert_eq!(list.next_index, 1);
+        assert_eq!(list.distributor_id, Some(accounts(1)));
+    }
+
+    #[test]
+    fn test_distribute_ft_from_list_in_chunks() {
+        let mut context = get_context(accounts(1));
+        let (mut contract, list_id) = two_entry_list(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(5))
+            .attached_deposit(0)
+            .build());
+        let msg = json!({"list_id": list_id, "from_index": 0, "limit": 1});
+        contract.ft_on_transfer(accounts(3), 10u128.into(), msg.to_string());
+        let list = contract.get_list(list_id).unwrap();
+        assert_eq!(list.next_index, 1);
+        assert_eq!(list.distributor_id, Some(accounts(3)));
+
+        let msg = json!({"list_id": list_id, "from_index": 1, "limit": 1});
+        contract.ft_on_transfer(accounts(3), 20u128.into(), msg.to_string());
+        assert_eq!(contract.get_account_quota(&accounts(3)), 1);
+        let list = contract.get_list(list_id).unwrap();
+        assert_eq!(list.next_index, 0);
+        assert_eq!(list.distributor_id, None);
+    }
+
+    #[test]
+    fn test_list_storage_released_on_delete() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(ONE_NEAR).build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        // the attached deposit registers the caller
+        let list_id = contract.create_list();
+        let registration = contract.storage_balance_bounds().min.0;
+        let created = storage_used(&contract, accounts(1));
+        assert!(created > registration);
+        contract.append_to_list(
+            list_id,
+            vec![ListEntry {
+                account_id: accounts(2),
+                amount: 10u128.into(),
+            }],
+        );
+        assert!(storage_used(&contract, accounts(1)) > created);
+        contract.finalize_list(list_id);
+        contract.increase_account_quota(&accounts(3), 1);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(0)
+            .build());
+        contract.ft_on_transfer(
+            accounts(3),
+            10u128.into(),
+            format!("{{\"list_id\":{}}}", list_id),
+        );
+        assert_eq!(contract.get_account_quota(&accounts(3)), 0);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.delete_list(list_id);
+        assert!(contract.get_list(list_id).is_none());
+        assert_eq!(storage_used(&contract, accounts(1)), registration);
+    }
+
+    #[test]
+    fn test_execute_due_schedule_period() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(ONE_NEAR).build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        contract.increase_account_quota(&accounts(1), 1);
+        let list_id = contract.create_list();
+        contract.append_to_list(
+            list_id,
+            vec![ListEntry {
+                account_id: accounts(2),
+                amount: 10u128.into(),
+            }],
+        );
+        contract.finalize_list(list_id);
+        let listed = storage_used(&contract, accounts(1));
+        let schedule_id = contract.create_schedule(list_id, None, 0.into(), 100.into(), 150.into());
+        // the records of both periods are reserved
+        let scheduled = storage_used(&contract, accounts(1));
+        assert!(scheduled > listed + 2 * 150 * env::storage_byte_cost());
+        testing_env!(context.attached_deposit(25).build());
+        contract.fund_schedule(schedule_id);
+
+        // A keeper executes the first period.
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(0)
+            .block_timestamp(50)
+            .build());
+        contract.execute_due(schedule_id);
+        let schedule = contract.get_schedule(schedule_id).unwrap();
+        assert_eq!(schedule.next_period, 1);
+        assert_eq!(schedule.balance.0, 15);
+        assert_eq!(contract.get_account_quota(&accounts(1)), 0);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.cancel_schedule(schedule_id);
+        let schedule = contract.get_schedule(schedule_id).unwrap();
+        assert!(schedule.cancelled);
+        assert_eq!(schedule.balance.0, 0);
+        // the record of the second period is released
+        assert_eq!(
+            storage_used(&contract, accounts(1)),
+            scheduled - 150 * env::storage_byte_cost()
+        );
+        contract.delete_list(list_id);
+    }
+
+    fn finalized_list(contract: &mut Contract) -> u64 {
+        let list_id = contract.create_list();
+        contract.append_to_list(
+            list_id,
+            vec![ListEntry {
+                account_id: accounts(2),
+                amount: 10u128.into(),
+            }],
+        );
+        contract.finalize_list(list_id);
+        list_id
+    }
+
+    #[test]
+    #[should_panic(expected = "A schedule has at most 10000 periods")]
+    fn test_schedule_periods_capped() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(ONE_NEAR).build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        let list_id = finalized_list(&mut contract);
+        contract.create_schedule(list_id, None, 0.into(), 1.into(), u64::MAX.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "A scheduled list has at most 50 receivers")]
+    fn test_schedule_receivers_capped() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(ONE_NEAR).build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        let list_id = contract.create_list();
+        let entries = (0..=MAX_SCHEDULE_RECEIVERS)
+            .map(|_| ListEntry {
+                account_id: accounts(2),
+                amount: 10u128.into(),
+            })
+            .collect();
+        contract.append_to_list(list_id, entries);
+        contract.finalize_list(list_id);
+        contract.create_schedule(list_id, None, 0.into(), 100.into(), 150.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gas attached")]
+    fn test_execute_due_checks_gas() {
+        assert!(execute_due_gas(true, MAX_SCHEDULE_RECEIVERS) < Gas(250_000_000_000_000));
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(ONE_NEAR).build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        contract.increase_account_quota(&accounts(1), 1);
+        let list_id = finalized_list(&mut contract);
+        let schedule_id = contract.create_schedule(list_id, None, 0.into(), 100.into(), 150.into());
+        testing_env!(context.attached_deposit(10).build());
+        contract.fund_schedule(schedule_id);
+        testing_env!(context
+            .attached_deposit(0)
+            .prepaid_gas(execute_due_gas(false, 0))
+            .build());
+        contract.execute_due(schedule_id);
+    }
+
+    #[test]
+    fn test_cancel_token_schedule_refund_is_claimable() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(ONE_NEAR).build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        let list_id = finalized_list(&mut contract);
+        let schedule_id =
+            contract.create_schedule(list_id, Some(accounts(5)), 0.into(), 100.into(), 150.into());
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        contract.fund_token_schedule(schedule_id, 25);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.cancel_schedule(schedule_id);
+        let callbacks: Vec<String> = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall { function_name, .. } => Some(function_name),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(callbacks, vec!["ft_transfer", "callback_refund_funds"]);
+        testing_env_with_result(accounts(0), PromiseResult::Failed);
+        contract.callback_refund_funds(accounts(5), accounts(1), 25.into());
+        assert_eq!(contract.token_refund(accounts(1), accounts(5)).0, 25);
+    }
+
+    #[test]
+    fn test_vesting_unlocks_linearly_after_cliff() {
+        let mut context = get_context(accounts(4));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        register_storage(&mut contract, accounts(3));
+        testing_env!(context.build());
+        contract.increase_account_quota(&accounts(3), 1);
+        let msg = json!({
+            "receivers": [accounts(2)],
+            "amount": ["1000"],
+            "vesting": {"start_at": "100", "cliff": "50", "duration": "200", "revocable": true},
+        });
+        contract.ft_on_transfer(accounts(3), 1000u128.into(), msg.to_string());
+        let vesting = &contract.vesting_of(accounts(2), None, None)[0];
+        assert_eq!(vesting.vesting.token_id, accounts(4));
+        assert_eq!(vesting.vested.0, 0);
+
+        testing_env!(context.block_timestamp(149).build());
+        assert_eq!(contract.vesting_of(accounts(2), None, None)[0].vested.0, 0);
+        testing_env!(context.block_timestamp(200).build());
+        assert_eq!(
+            contract.vesting_of(accounts(2), None, None)[0]
+                .withdrawable
+                .0,
+            500
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.revoke_vesting(0);
+        testing_env!(context.block_timestamp(400).build());
+        let vesting = &contract.vesting_of(accounts(2), None, None)[0];
+        assert_eq!(vesting.vesting.total.0, 500);
+        assert_eq!(vesting.withdrawable.0, 500);
+    }
+
+    fn vesting_contract(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        register_storage(&mut contract, accounts(3));
+        testing_env!(context.build());
+        contract.increase_account_quota(&accounts(3), 2);
+        let msg = json!({
+            "receivers": [accounts(2), accounts(2)],
+            "amount": ["100", "200"],
+            "vesting": {"start_at": "0", "cliff": "0", "duration": "100"},
+        });
+        contract.ft_on_transfer(accounts(3), 300u128.into(), msg.to_string());
+        contract
+    }
+
+    #[test]
+    fn test_withdraw_vested_by_ids() {
+        let mut context = get_context(accounts(4));
+        let mut contract = vesting_contract(&mut context);
+        assert_eq!(
+            contract.vesting_of(accounts(2), Some(1), Some(1))[0].vesting_id,
+            1
+        );
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(50)
+            .build());
+        contract.withdraw_vested(vec![1]);
+        let vestings = contract.vesting_of(accounts(2), None, None);
+        assert_eq!(vestings[0].withdrawable.0, 50);
+        assert_eq!(vestings[1].withdrawable.0, 0);
+
+        // the failed transfer is withdrawable again
+        testing_env_with_result(accounts(0), PromiseResult::Failed);
+        contract.callback_withdraw_vested(accounts(2), vec![(1, 100.into())]);
+        assert_eq!(contract.vestings.get(&1).unwrap().withdrawn.0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Withdraw 1 to 20 vestings at a time")]
+    fn test_withdraw_vested_is_capped() {
+        let mut context = get_context(accounts(4));
+        let mut contract = vesting_contract(&mut context);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.withdraw_vested(vec![0; MAX_VESTED_WITHDRAWALS + 1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the beneficiary can withdraw the vesting")]
+    fn test_withdraw_vested_of_beneficiary_only() {
+        let mut context = get_context(accounts(4));
+        let mut contract = vesting_contract(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(50)
+            .build());
+        contract.withdraw_vested(vec![0]);
+    }
+
+    #[test]
+    fn test_failed_token_refund_is_claimable() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        register_storage(&mut contract, accounts(3));
+        // e.g. the unvested tokens of a revoked vesting the sender cannot receive
+        testing_env_with_result(accounts(0), PromiseResult::Failed);
+        contract.callback_refund_funds(accounts(4), accounts(3), 500.into());
+        testing_env_with_result(accounts(0), PromiseResult::Successful(vec![]));
+        contract.callback_refund_funds(accounts(4), accounts(3), 200.into());
+        assert_eq!(contract.token_refund(accounts(3), accounts(4)).0, 500);
+        assert!(storage_used(&contract, accounts(3)) > contract.storage_balance_bounds().min.0);
+
+        testing_env!(get_context(accounts(3)).build());
+        contract.claim_token_refund(accounts(4));
+        assert_eq!(contract.token_refund(accounts(3), accounts(4)).0, 0);
+        assert_eq!(
+            storage_used(&contract, accounts(3)),
+            contract.storage_balance_bounds().min.0
+        );
+    }
+
+    #[test]
+    fn test_time_locked_distribution() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(30).build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        register_storage(&mut contract, accounts(1));
+        testing_env!(context.attached_deposit(30).build());
+        contract.increase_account_quota(&accounts(1), 4);
+        let options = DistributeOptions {
+            release_at: Some(100.into()),
+            ..Default::default()
+        };
+        let escrow_id = contract.distribute_near(
+            vec![accounts(2), accounts(3)],
+            vec![10u128.into(), 20u128.into()],
+            Some(options),
+        );
+        assert_eq!(escrow_id, Some(0));
+        assert_eq!(contract.get_escrow(0).unwrap().release_at.0, 100);
+        assert_eq!(contract.get_account_quota(&accounts(1)), 2);
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.contains("\"event\":\"create_escrow\"")
+                && log.contains("\"escrow_id\":0")
+                && log.contains("\"total_amount\":\"30\"")));
+
+        contract.cancel_escrow(0);
+        assert!(contract.get_escrow(0).is_none());
+        assert!(contract.escrows_of(accounts(1), None, None).is_empty());
+        assert_eq!(contract.get_account_quota(&accounts(1)), 4);
+
+        let options = DistributeOptions {
+            release_at: Some(100.into()),
+            ..Default::default()
+        };
+        let escrow_id = contract.distribute_near(
+            vec![accounts(2), accounts(3)],
+            vec![10u128.into(), 20u128.into()],
+            Some(options),
+        );
+        assert_eq!(escrow_id, Some(1));
+        let escrows = contract.escrows_of(accounts(1), None, None);
+        assert_eq!(escrows.len(), 1);
+        assert_eq!(escrows[0].escrow_id, 1);
+        assert_eq!(escrows[0].escrow.receivers, vec![accounts(2), accounts(3)]);
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(0)
+            .block_timestamp(100)
+            .build());
+        contract.release_escrow(1);
+        assert!(contract.get_escrow(1).is_none());
+        assert!(contract.escrows_of(accounts(1), None, None).is_empty());
+        assert!(get_logs()
+            .iter()
+            .any(|log| log.contains("\"event\":\"release_escrow\"")));
+    }
+
+    #[test]
+    fn test_split_modes_round_down() {
+        let shares = SplitMode::Equal.split(100, 3, &[]);
+        assert_eq!(
+            shares.iter().map(|x| x.0).collect::<Vec<_>>(),
+            vec![33, 33, 33]
+        );
+
+        let weights: Vec<U128> = vec![2500u128.into(), 7500u128.into()];
+        let shares = SplitMode::Weighted.split(1001, 2, &weights);
+        assert_eq!(
+            shares.iter().map(|x| x.0).collect::<Vec<_>>(),
+            vec![250, 750]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Amount too small to split")]
+    fn test_split_rejects_zero_shares() {
+        SplitMode::Equal.split(2, 3, &[]);
+    }
+
+    #[test]
+    fn test_parse_legacy_distribution_message() {
+        let msg = FtDistributeMsg::parse("bob.testnet:20#alice.testnet:50").unwrap();
+        assert_eq!(
+            msg.receivers,
+            vec![
+                AccountId::new_unchecked("bob.testnet".to_string()),
+                AccountId::new_unchecked("alice.testnet".to_string())
+            ]
+        );
+        assert_eq!(msg.amount, vec![U128(20), U128(50)]);
+
+        assert_eq!(
+            FtDistributeMsg::parse("bob.testnet").err().unwrap(),
+            "Invalid distribution entry \"bob.testnet\", expected \"account_id:amount\""
+        );
+        assert_eq!(
+            FtDistributeMsg::parse("Bob!:20").err().unwrap(),
+            "Invalid receiver account id \"Bob!\""
+        );
+        assert_eq!(
+            FtDistributeMsg::parse("bob.testnet:20#alice.testnet:x")
+                .err()
+                .unwrap(),
+            "Invalid amount \"x\" for receiver alice.testnet"
+        );
+        assert!(FtDistributeMsg::parse("{\"receivers\": 1}")
+            .err()
+            .unwrap()
+            .starts_with("Invalid distribution message"));
+    }
+
+    #[test]
+    fn test_weighted_split_large_amounts() {
+        let weights: Vec<U128> = vec![(u128::MAX / 4).into(), (u128::MAX / 4 * 3).into()];
+        let shares = SplitMode::Weighted.split(1_000_000 * ONE_NEAR, 2, &weights);
+        assert_eq!(
+            shares.iter().map(|x| x.0).collect::<Vec<_>>(),
+            vec![250_000 * ONE_NEAR, 750_000 * ONE_NEAR]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Sum of weights overflows")]
+    fn test_weighted_split_rejects_weight_overflow() {
+        let weights: Vec<U128> = vec![u128::MAX.into(), 1u128.into()];
+        SplitMode::Weighted.split(ONE_NEAR, 2, &weights);
+    }
+
+    #[test]
+    #[should_panic(expected = "weights must not all be zero")]
+    fn test_weighted_split_rejects_zero_weights() {
+        SplitMode::Weighted.split(ONE_NEAR, 2, &[0u128.into(), 0u128.into()]);
+    }
+
+    #[test]
+    fn test_duplicate_receivers_aggregated() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let (receivers, amount) = DuplicateMode::Aggregate.apply(
+            &accounts(1),
+            vec![accounts(2), accounts(3), accounts(2)],
+            vec![10u128.into(), 20u128.into(), 5u128.into()],
+        );
+        assert_eq!(receivers, vec![accounts(2), accounts(3)]);
+        assert_eq!(amount.iter().map(|x| x.0).collect::<Vec<_>>(), vec![15, 20]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Duplicate receiver")]
+    fn test_duplicate_receivers_rejected() {
+        DuplicateMode::Reject.apply(
+            &accounts(1),
+            vec![accounts(2), accounts(2)],
+            vec![10u128.into(), 5u128.into()],
+        );
+    }
+
+    #[test]
+    fn test_validate_near_batch() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        contract.increase_account_quota(&accounts(1), 3);
+        let receivers = vec![
+            accounts(2).to_string(),
+            "Not Valid".to_string(),
+            accounts(2).to_string(),
+        ];
+        let amounts = vec![10u128.into(), 20u128.into(), 5u128.into()];
+        let report = contract.validate_batch(receivers, amounts, accounts(1));
+        assert_eq!(report.total_amount.0, 35);
+        assert_eq!(report.quota_available, Some(3));
+        assert!(!report.receivers[1].valid_account_id);
+        assert!(report.receivers[2].duplicate);
+        assert!(!report.valid);
+    }
+
+    #[test]
+    fn test_validate_token_batch_registrations() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        contract.increase_account_quota(&accounts(1), 3);
+        let receivers = vec![
+            accounts(2).to_string(),
+            "Not Valid".to_string(),
+            accounts(3).to_string(),
+        ];
+        let amounts = vec![10u128.into(); 3];
+        assert!(matches!(
+            contract.validate_token_batch(accounts(5), receivers.clone(), amounts.clone(), None),
+            PromiseOrValue::Promise(_)
+        ));
+        // the callback completes the report of the synchronous checks
+        let report = contract.validate_batch(receivers, amounts, accounts(1));
+        // only the valid receivers are queried
+        testing_env!(
+            get_context(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![
+                PromiseResult::Successful(b"null".to_vec()),
+                PromiseResult::Successful(b"{\"total\":\"1\",\"available\":\"0\"}".to_vec()),
+            ],
+        );
+        let report = contract.callback_validate_batch(report);
+        assert_eq!(report.receivers[0].registered, Some(false));
+        assert_eq!(report.receivers[1].registered, None);
+        assert_eq!(report.receivers[2].registered, Some(true));
+        assert!(!report.valid);
+    }
+
+    #[test]
+    #[should_panic(expected = "is rejected")]
+    fn test_reject_implicit_accounts() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let implicit_id = AccountId::new_unchecked("a".repeat(64));
+        assert!(is_implicit_account(&implicit_id));
+        assert!(!is_implicit_account(&accounts(2)));
+        AccountPolicy::RejectImplicit.check(&[accounts(2), implicit_id]);
+    }
+
+    #[test]
+    fn test_reject_missing_named_accounts() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        let implicit_id = AccountId::new_unchecked("a".repeat(64));
+        assert!(named_account_checks(std::slice::from_ref(&implicit_id)).is_none());
+        let charge = QuotaCharge {
+            owner_id: accounts(3),
+            spender_id: accounts(3),
+            amount: 3,
+            from_subscription: 0,
+            from_balance: 3,
+        };
+        // only the named receivers are checked
+        testing_env!(
+            get_context(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![]), PromiseResult::Failed],
+        );
+        contract.callback_check_named_accounts(
+            vec![accounts(1), implicit_id, accounts(2)],
+            vec![10.into(), 20.into(), 30.into()],
+            charge,
+        );
+        assert_eq!(contract.get_account_quota(&accounts(3)), 3);
+        let logs = get_logs();
+        assert!(logs
+            .iter()
+            .any(|log| log.contains("reject_missing_accounts")
+                && log.contains(&format!("[\"{}\"]", accounts(2)))
+                && log.contains("\"refund_amount\":\"60\"")));
+        assert!(logs.iter().any(|log| log.contains("refund_near")));
+    }
+
+    #[test]
+    fn test_reject_missing_named_sends_when_all_exist() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        testing_env!(
+            get_context(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![
+                PromiseResult::Successful(vec![]),
+                PromiseResult::Successful(vec![])
+            ],
+        );
+        contract.callback_check_named_accounts(
+            vec![accounts(1), accounts(2)],
+            vec![10.into(), 20.into()],
+            pending_swap().charge,
+        );
+        assert_eq!(contract.get_account_quota(&accounts(3)), 0);
+        assert!(get_logs().is_empty());
+    }
+
+    #[test]
+    fn test_wrap_near_distribution_covers_storage() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context
+            .attached_deposit(30 + 2 * DEFAULT_WRAP_STORAGE_DEPOSIT)
+            .build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        contract.increase_account_quota(&accounts(1), 2);
+        contract.wrap_token_registered = true;
+        let options = DistributeOptions {
+            wrap_near: true,
+            ..Default::default()
+        };
+        contract.distribute_near(
+            vec![accounts(2), accounts(3)],
+            vec![10u128.into(), 20u128.into()],
+            Some(options),
+        );
+        assert_eq!(contract.get_account_quota(&accounts(1)), 0);
+        assert_eq!(contract.wrap_token().0.as_str(), "wrap.near");
+    }
+
+    #[test]
+    fn test_set_wrap_token_registers_contract() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        testing_env!(context
+            .attached_deposit(DEFAULT_WRAP_STORAGE_DEPOSIT + 5)
+            .build());
+        contract.set_wrap_token(accounts(5), DEFAULT_WRAP_STORAGE_DEPOSIT.into());
+        assert!(!contract.wrap_token_registered);
+        assert!(get_logs().iter().any(|log| log.contains("refund_near")));
+
+        testing_env_with_result(accounts(0), PromiseResult::Failed);
+        contract.callback_wrap_token_registered(accounts(5), DEFAULT_WRAP_STORAGE_DEPOSIT.into());
+        assert!(!contract.wrap_token_registered);
+        testing_env_with_result(accounts(0), PromiseResult::Successful(vec![]));
+        contract.callback_wrap_token_registered(accounts(5), DEFAULT_WRAP_STORAGE_DEPOSIT.into());
+        assert!(contract.wrap_token_registered);
+        assert_eq!(contract.wrap_token().0, accounts(5));
+    }
+
+    #[test]
+    #[should_panic(expected = "The contract is not registered on the wrap token")]
+    fn test_wrap_near_distribution_requires_registration() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context
+            .attached_deposit(10 + DEFAULT_WRAP_STORAGE_DEPOSIT)
+            .build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        contract.increase_account_quota(&accounts(1), 1);
+        let options = DistributeOptions {
+            wrap_near: true,
+            ..Default::default()
+        };
+        contract.distribute_near(vec![accounts(2)], vec![10u128.into()], Some(options));
+    }
+
+    #[test]
+    fn test_wrap_near_failed_registration_lookup_refunds_sender() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        contract.increase_account_quota(&accounts(1), 2);
+        let charge = contract.charge_quota(None, accounts(1), 2);
+        testing_env!(
+            get_context(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![
+                PromiseResult::Successful(vec![]),
+                PromiseResult::Successful(b"null".to_vec()),
+                PromiseResult::Failed,
+            ],
+        );
+        contract.callback_wrap_near(
+            vec![accounts(2), accounts(3)],
+            vec![10u128.into(), 20u128.into()],
+            charge,
+            (2 * DEFAULT_WRAP_STORAGE_DEPOSIT).into(),
+        );
+        assert_eq!(contract.get_account_quota(&accounts(1)), 2);
+        let calls: Vec<String> = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::FunctionCall { function_name, .. } => Some(function_name),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(calls, vec!["near_withdraw", "callback_unwrap_refund"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "wrap_near batches are limited to 20 receivers")]
+    fn test_wrap_near_distribution_is_capped() {
+        let receivers: Vec<AccountId> = (0..=MAX_WNEAR_RECEIVERS)
+            .map(|i| AccountId::new_unchecked(format!("r{}.near", i)))
+            .collect();
+        let mut context = get_context(accounts(1));
+        testing_env!(context
+            .attached_deposit(receivers.len() as u128 * (1 + DEFAULT_WRAP_STORAGE_DEPOSIT))
+            .build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        contract.increase_account_quota(&accounts(1), receivers.len() as u128);
+        let amount = vec![1u128.into(); receivers.len()];
+        contract.wrap_token_registered = true;
+        let options = DistributeOptions {
+            wrap_near: true,
+            ..Default::default()
+        };
+        contract.distribute_near(receivers, amount, Some(options));
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gas attached")]
+    fn test_wrap_near_distribution_checks_gas() {
+        assert!(distribute_wnear_gas(MAX_WNEAR_RECEIVERS as u64) < Gas(250_000_000_000_000));
+        let mut context = get_context(accounts(1));
+        testing_env!(context
+            .attached_deposit(30 + 2 * DEFAULT_WRAP_STORAGE_DEPOSIT)
+            .prepaid_gas(distribute_wnear_gas(1))
+            .build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        contract.increase_account_quota(&accounts(1), 2);
+        contract.wrap_token_registered = true;
+        let options = DistributeOptions {
+            wrap_near: true,
+            ..Default::default()
+        };
+        contract.distribute_near(
+            vec![accounts(2), accounts(3)],
+            vec![10u128.into(), 20u128.into()],
+            Some(options),
+        );
+    }
+
+    #[test]
+    fn test_migrate_from_v1() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut balances = LookupMap::new(StorageKey::BalanceData);
+        balances.insert(&accounts(2), &7);
+        env::state_write(&ContractV1 {
+            owner_id: accounts(1),
+            oracle_id: accounts(3),
+            balances,
+            account_storage_usage: 0,
+            service_fee: 0.into(),
+        });
+
+        let contract = Contract::migrate(Some(OracleMode::Oracle(FluxSource {
+            oracle_account_id: accounts(4),
+            provider_id: accounts(5),
+        })));
+        assert_eq!(contract.state_version(), STATE_VERSION);
+        assert_eq!(contract.owner_id(), accounts(1));
+        assert_eq!(contract.oracle(), Some((accounts(4), accounts(5))));
+        assert_eq!(contract.get_account_quota(&accounts(2)), 7);
+    }
+
+    #[test]
+    fn test_migrate_latest_version_keeps_state() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        contract.increase_account_quota(&accounts(2), 3);
+        contract.set_referral_fee(500);
+        env::state_write(&contract);
+
+        let contract = Contract::migrate(None);
+        assert_eq!(contract.state_version(), STATE_VERSION);
+        assert_eq!(
+            contract.oracle(),
+            Some((
+                AccountId::new_unchecked("id".to_string()),
+                AccountId::new_unchecked("id".to_string())
+            ))
+        );
+        assert_eq!(contract.get_account_quota(&accounts(2)), 3);
+        assert_eq!(contract.referral_fee(), 500);
+    }
+
+    #[test]
+    fn test_migrate_from_v2() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut balances = LookupMap::new(StorageKey::BalanceData);
+        balances.insert(&accounts(2), &7);
+        env::state_write(&ContractV2 {
+            owner_id: accounts(1),
+            balances,
+            oracle_account_id: accounts(4),
+            oracle_provider_id: accounts(5),
+        });
+
+        let contract = Contract::migrate(None);
+        assert_eq!(contract.state_version(), STATE_VERSION);
+        assert_eq!(contract.owner_id(), accounts(1));
+        assert_eq!(contract.oracle(), Some((accounts(4), accounts(5))));
+        assert_eq!(contract.get_account_quota(&accounts(2)), 7);
+        assert_eq!(contract.fee_revenue().held_near.0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only owner can update the code")]
+    fn test_migrate_requires_owner() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        env::state_write(&contract);
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        Contract::migrate(None);
+    }
+
+    #[test]
+    fn test_deploy_staged_code_after_timelock() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(ONE_NEAR).build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        let code_hash = contract.stage_code(vec![1, 2, 3].into());
+        let upgrade = contract.staged_code().unwrap();
+        assert_eq!(upgrade.code_hash, code_hash);
+        assert_eq!(upgrade.deployable_at.0, UPGRADE_TIMELOCK);
+        assert!(upgrade.storage_deposit.0 > 0);
+
+        testing_env!(context
+            .attached_deposit(0)
+            .block_timestamp(UPGRADE_TIMELOCK)
+            .build());
+        contract.deploy_staged_code();
+        assert!(contract.staged_code().is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Staged code is not deployable yet")]
+    fn test_deploy_staged_code_before_timelock() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.attached_deposit(ONE_NEAR).build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        contract.stage_code(vec![1, 2, 3].into());
+        testing_env!(context.block_timestamp(UPGRADE_TIMELOCK - 1).build());
+        contract.deploy_staged_code();
+    }
+
+    fn oracle_entry(price: u128, decimals: u32) -> OracleEntry {
+        OracleEntry {
+            price: price.into(),
+            decimals,
+            last_update: 0,
+        }
+    }
+
+    #[test]
+    fn test_aggregate_prices_median() {
+        let entries = vec![
+            oracle_entry(300, 2),
+            oracle_entry(31_000, 4),
+            oracle_entry(290, 2),
+        ];
+        let entry = aggregate_prices(entries, 2, MAX_BASIS_POINTS).unwrap();
+        assert_eq!(entry.decimals, PRICE_DECIMALS);
+        assert_eq!(entry.price.0, 300 * 10u128.pow(6));
+
+        // two prices agree, the median of both is used
+        let entries = vec![oracle_entry(300, 2), oracle_entry(310, 2)];
+        assert_eq!(
+            aggregate_prices(entries, 1, 1000).unwrap().price.0,
+            305 * 10u128.pow(6)
+        );
+    }
+
+    #[test]
+    fn test_aggregate_prices_quorum_and_deviation() {
+        // the outlier deviates more than 10% from the median
+        let entries = vec![
+            oracle_entry(300, 2),
+            oracle_entry(305, 2),
+            oracle_entry(900, 2),
+        ];
+        assert_eq!(
+            aggregate_prices(entries, 2, 1000).unwrap().price.0,
+            302 * 10u128.pow(6) + 5 * 10u128.pow(5)
+        );
+        let entries = vec![
+            oracle_entry(300, 2),
+            oracle_entry(305, 2),
+            oracle_entry(900, 2),
+        ];
+        assert!(aggregate_prices(entries, 3, 1000).is_none());
+        // a failed source leaves fewer prices than the quorum
+        assert!(aggregate_prices(vec![oracle_entry(300, 2)], 2, 1000).is_none());
+        assert!(aggregate_prices(Vec::new(), 1, 1000).is_none());
+    }
+
+    #[test]
+    fn test_aggregate_prices_ignores_overflow() {
+        // a source with absurd decimals is an outlier and the honest median is used
+        let entries = vec![
+            oracle_entry(300, 2),
+            oracle_entry(305, 2),
+            oracle_entry(3 * 10u128.pow(38), 40),
+        ];
+        let price = aggregate_prices(entries, 2, 1000).unwrap();
+        assert_eq!(price.price.0, 302 * 10u128.pow(6) + 5 * 10u128.pow(5));
+        assert_eq!(price.decimals, PRICE_DECIMALS);
+        // the price that overflows at `PRICE_DECIMALS` decimals is dropped
+        let entries = vec![oracle_entry(u128::MAX, 0), oracle_entry(300, 2)];
+        let price = aggregate_prices(entries, 1, MAX_BASIS_POINTS).unwrap();
+        assert_eq!(price.price.0, 300 * 10u128.pow(6));
+        let entries = vec![oracle_entry(u128::MAX, 8), oracle_entry(u128::MAX - 2, 8)];
+        let price = aggregate_prices(entries, 2, MAX_BASIS_POINTS).unwrap();
+        assert_eq!(price.price.0, u128::MAX - 1);
+    }
+
+    #[test]
+    fn test_decode_flux_entry() {
+        let source = FluxSource {
+            oracle_account_id: accounts(3),
+            provider_id: accounts(4),
+        };
+        let payload = br#"{"price":"3050000","decimals":6,"last_update":1650000000000000000}"#;
+        let entry = source.decode(payload).unwrap();
+        assert_eq!(entry.price.0, 3_050_000);
+        assert_eq!(entry.decimals, 6);
+        assert_eq!(entry.last_update, 1_650_000_000_000_000_000);
+        assert!(source.decode(br#"{"multiplier":"30500"}"#).is_none());
+    }
+
+    #[test]
+    fn test_decode_price_oracle_data() {
+        let source = PriceOracleSource {
+            oracle_account_id: accounts(3),
+            asset_id: AccountId::new_unchecked("wrap.near".to_string()),
+        };
+        let payload = br#"{
+            "timestamp": "1650000000000000000",
+            "recency_duration_sec": 90,
+            "prices": [
+                {"asset_id": "usdt.near", "price": {"multiplier": "10000", "decimals": 10}},
+                {"asset_id": "wrap.near", "price": {"multiplier": "30500", "decimals": 28}}
+            ]
+        }"#;
+        let entry = source.decode(payload).unwrap();
+        assert_eq!(entry.price.0, 30_500);
+        assert_eq!(entry.decimals, 4);
+        assert_eq!(entry.last_update, 1_650_000_000_000_000_000);
+        assert_eq!(entry.usd_to_yocto(305, 2), ONE_NEAR);
+
+        let missing = br#"{
+            "timestamp": "1650000000000000000",
+            "recency_duration_sec": 90,
+            "prices": [{"asset_id": "wrap.near", "price": null}]
+        }"#;
+        assert!(source.decode(missing).is_none());
+    }
+
+    #[test]
+    fn test_usd_to_yocto_decimals() {
+        let entry = oracle_entry(30_500, 4);
+        assert_eq!(entry.usd_to_yocto(305, 2), ONE_NEAR);
+        assert_eq!(entry.usd_to_yocto(3_050_000, 6), ONE_NEAR);
+        assert_eq!(entry.usd_to_yocto(1, 60), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "USD value is too large to convert to yoctoNEAR")]
+    fn test_usd_to_yocto_rejects_overflow() {
+        oracle_entry(30_500, 28).usd_to_yocto(1_000_000, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Oracle price must be positive")]
+    fn test_usd_to_yocto_rejects_zero_price() {
+        oracle_entry(0, 4).usd_to_yocto(305, 2);
+    }
+
+    #[test]
+    fn test_set_oracle_config() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        let source = PriceSource::Flux(FluxSource {
+            oracle_account_id: accounts(3),
+            provider_id: accounts(4),
+        });
+        contract.set_oracle_config(OracleConfig {
+            sources: vec![source.clone()],
+            min_quorum: 2,
+            max_deviation_bps: 500,
+        });
+        let config = contract.oracle_config();
+        assert_eq!(config.sources, vec![source]);
+        assert_eq!(config.min_quorum, 2);
+    }
+
+    #[test]
+    fn test_storage_charged_and_released() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        let min_balance = contract.storage_balance_bounds().min.0;
+        assert!(contract.storage_balance_of(accounts(1)).is_none());
+        register_storage(&mut contract, accounts(1));
+        let balance = contract.storage_balance_of(accounts(1)).unwrap();
+        assert_eq!(balance.total.0, ONE_NEAR);
+        assert_eq!(balance.available.0, ONE_NEAR - min_balance);
+
+        testing_env!(context.build());
+        contract.approve_quota_spender(accounts(2), 4);
+        let charged = contract
+            .storage_balance_of(accounts(1))
+            .unwrap()
+            .available
+            .0;
+        assert!(charged < ONE_NEAR - min_balance);
+        contract.approve_quota_spender(accounts(2), 0);
+        let balance = contract.storage_balance_of(accounts(1)).unwrap();
+        assert_eq!(balance.available.0, ONE_NEAR - min_balance);
+
+        testing_env!(context.attached_deposit(1).build());
+        let balance = contract.storage_withdraw(None);
+        assert_eq!(balance.total.0, min_balance);
+        assert!(contract.storage_unregister(None));
+        assert!(contract.storage_balance_of(accounts(1)).is_none());
+    }
+
+    #[test]
+    fn test_swap_distribution_charges_quota() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        contract.set_dex(Some(accounts(5)));
+        contract.increase_account_quota(&accounts(3), 2);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        let msg = json!({
+            "receivers": [accounts(1), accounts(2)],
+            "amount": ["10", "20"],
+            "swap": {"pool_id": 7, "token_out": "wrap.near", "min_amount_out": "31"},
+        });
+        contract.ft_on_transfer(accounts(3), 100u128.into(), msg.to_string());
+        assert_eq!(contract.get_account_quota(&accounts(3)), 0);
+    }
+
+    fn pending_swap() -> PendingSwap {
+        PendingSwap {
+            dex_id: accounts(5),
+            token_in: accounts(4),
+            amount_in: 100.into(),
+            step: SwapStep {
+                pool_id: 7,
+                token_out: AccountId::new_unchecked("wrap.near".to_string()),
+                min_amount_out: 31.into(),
+            },
+            receivers: vec![accounts(1), accounts(2)],
+            amount: vec![10.into(), 20.into()],
+            charge: QuotaCharge {
+                owner_id: accounts(3),
+                spender_id: accounts(3),
+                amount: 2,
+                from_subscription: 0,
+                from_balance: 2,
+            },
+        }
+    }
+
+    fn testing_env_with_result(predecessor_account_id: AccountId, result: PromiseResult) {
+        testing_env!(
+            get_context(predecessor_account_id).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result],
+        );
+    }
+
+    #[test]
+    fn test_swap_partial_deposit_is_withdrawable() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        testing_env_with_result(accounts(0), PromiseResult::Successful(b"\"40\"".to_vec()));
+        let refund = contract.callback_swap_deposit(pending_swap());
+        assert_eq!(refund.0, 60);
+        assert_eq!(contract.get_account_quota(&accounts(3)), 2);
+        assert_eq!(
+            contract.dex_refund(accounts(3), accounts(5), accounts(4)).0,
+            40
+        );
+    }
+
+    #[test]
+    fn test_swap_failed_withdraw_can_be_retried() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        let wrap_id = AccountId::new_unchecked("wrap.near".to_string());
+        testing_env_with_result(accounts(0), PromiseResult::Failed);
+        contract.callback_swap_withdraw(pending_swap(), 35.into());
+        assert_eq!(contract.get_account_quota(&accounts(3)), 2);
+        assert_eq!(
+            contract
+                .dex_refund(accounts(3), accounts(5), wrap_id.clone())
+                .0,
+            35
+        );
+
+        testing_env!(get_context(accounts(3)).build());
+        contract.withdraw_dex_refund(accounts(5), wrap_id.clone());
+        assert_eq!(
+            contract
+                .dex_refund(accounts(3), accounts(5), wrap_id.clone())
+                .0,
+            0
+        );
+        // the retried withdrawal fails too, the tokens stay withdrawable
+        testing_env_with_result(accounts(0), PromiseResult::Failed);
+        contract.callback_swap_refund(accounts(5), wrap_id.clone(), accounts(3), 35.into());
+        assert_eq!(contract.dex_refund(accounts(3), accounts(5), wrap_id).0, 35);
+    }
+
+    #[test]
+    #[should_panic(expected = "min_amount_out must cover the distributed amount")]
+    fn test_swap_distribution_requires_covering_min_amount_out() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(
+            AccountId::new_unchecked("id".to_string()),
+            AccountId::new_unchecked("id".to_string()),
+        );
+        contract.set_dex(Some(accounts(5)));
+        contract.increase_account_quota(&accounts(3), 2);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        let msg = json!({
+            "receivers": [accounts(1), accounts(2)],
+            "amount": ["10", "20"],
+            "swap": {"pool_id": 7, "token_out": "wrap.near", "min_amount_out": "29"},
+        });
+        contract.ft_on_transfer(accounts(3), 100u128.into(), msg.to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "swap distributions are limited to 30 receivers")]
+    fn test_swap_distribution_is_capped() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        contract.set_dex(Some(accounts(5)));
+        contract.increase_account_quota(&accounts(3), MAX_SWAP_RECEIVERS as u128 + 1);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        let receivers: Vec<String> = (0..=MAX_SWAP_RECEIVERS)
+            .map(|i| format!("receiver{}.near", i))
+            .collect();
+        let msg = json!({
+            "receivers": receivers,
+            "amount": vec!["1"; MAX_SWAP_RECEIVERS + 1],
+            "swap": {"pool_id": 7, "token_out": "wrap.near", "min_amount_out": "100"},
+        });
+        contract.ft_on_transfer(accounts(3), 100u128.into(), msg.to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gas attached")]
+    fn test_swap_distribution_checks_gas() {
+        assert!(distribute_swap_gas(MAX_SWAP_RECEIVERS as u64) < Gas(250_000_000_000_000));
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(3), accounts(4));
+        contract.set_dex(Some(accounts(5)));
+        contract.increase_account_quota(&accounts(3), 2);
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .prepaid_gas(distribute_swap_gas(1))
+            .build());
+        let msg = json!({
+            "receivers": [accounts(1), accounts(2)],
+            "amount": ["10", "20"],
+            "swap": {"pool_id": 7, "token_out": "wrap.near", "min_amount_out": "31"},
+        });
+        contract.ft_on_transfer(accounts(3), 100u128.into(), msg.to_string());
+    }
+}
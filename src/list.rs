@@ -0,0 +1,232 @@
+use crate::*;
+
+/// One receiver of a recipient list.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ListEntry {
+    pub account_id: AccountId,
+    pub amount: U128,
+}
+
+/// Receivers uploaded in chunks by `owner_id`, so a distribution can be executed by reference
+/// with arguments small enough for a DAO proposal or argument size limits.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct RecipientList {
+    pub owner_id: AccountId,
+    pub entries: Vector<ListEntry>,
+    pub total_amount: Balance,
+    /// Finalized lists cannot be changed and can be distributed.
+    pub finalized: bool,
+    /// Index of the next entry of the distribution in progress, 0 if none is in progress.
+    pub next_index: u64,
+    /// Account running the distribution in progress.
+    pub distributor_id: Option<AccountId>,
+}
+
+/// Summary of a recipient list returned by `get_list`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RecipientListView {
+    pub owner_id: AccountId,
+    pub len: u64,
+    pub total_amount: U128,
+    pub finalized: bool,
+    pub next_index: u64,
+    pub distributor_id: Option<AccountId>,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// A payable method to create an empty recipient list owned by `env::predecessor_account_id`
+    /// and return its id. The storage used is charged to the storage balance of the caller, the
+    /// attached deposit is added to it first.
+    #[payable]
+    pub fn create_list(&mut self) -> u64 {
+        let owner_id = env::predecessor_account_id();
+        self.deposit_attached_storage(&owner_id);
+        let initial_storage_usage = env::storage_usage();
+        let list_id = self.next_list_id;
+        self.next_list_id += 1;
+        let list = RecipientList {
+            owner_id: owner_id.clone(),
+            entries: Vector::new(StorageKey::ListEntries { list_id }),
+            total_amount: 0,
+            finalized: false,
+            next_index: 0,
+            distributor_id: None,
+        };
+        self.lists.insert(&list_id, &list);
+        self.charge_storage(&owner_id, initial_storage_usage);
+        list_id
+    }
+
+    /// A payable method to append a chunk of receivers to `list_id`. The storage used is charged
+    /// to the storage balance of the caller, the attached deposit is added to it first.
+    ///
+    /// Requirements:
+    /// - The caller must be the list owner.
+    /// - The list must not be finalized.
+    #[payable]
+    pub fn append_to_list(&mut self, list_id: u64, entries: Vec<ListEntry>) {
+        let mut list = self.assert_list_owner(list_id);
+        assert!(!list.finalized, "List is finalized");
+        self.deposit_attached_storage(&list.owner_id);
+        let initial_storage_usage = env::storage_usage();
+        for entry in entries.iter() {
+            list.total_amount += entry.amount.0;
+            list.entries.push(entry);
+        }
+        self.lists.insert(&list_id, &list);
+        self.charge_storage(&list.owner_id, initial_storage_usage);
+    }
+
+    /// Delete `list_id` and release its storage to the storage balance of the owner.
+    ///
+    /// Requirements:
+    /// - The caller must be the list owner.
+    /// - No active schedule may use the list.
+    pub fn delete_list(&mut self, list_id: u64) {
+        let mut list = self.assert_list_owner(list_id);
+        assert!(
+            self.scheduled_lists.get(&list_id).is_none(),
+            "List is used by a schedule"
+        );
+        let initial_storage_usage = env::storage_usage();
+        list.entries.clear();
+        self.lists.remove(&list_id);
+        self.record_storage(&list.owner_id, initial_storage_usage);
+    }
+
+    /// Lock `list_id` so it can be distributed. A proposal referencing a finalized list
+    /// always pays the receivers that were voted on.
+    ///
+    /// Requirements:
+    /// - The caller must be the list owner.
+    /// - The list must not be empty.
+    pub fn finalize_list(&mut self, list_id: u64) {
+        let mut list = self.assert_list_owner(list_id);
+        assert!(!list.entries.is_empty(), "List is empty");
+        list.finalized = true;
+        self.lists.insert(&list_id, &list);
+    }
+
+    /// A payable method to distribute NEAR to the receivers of the finalized `list_id` in chunks,
+    /// works like `distribute_near`. Each call sends the `limit` entries from `from_index`, the
+    /// attached deposit must equal their total and quota is taken from the caller or
+    /// `options.quota_owner_id`. The progress is stored with the list: `from_index` 0 starts a
+    /// distribution, which only its caller continues from `next_index` (see `get_list`) until
+    /// the last entry. Return the escrow id of an escrowed chunk, `None` otherwise.
+    ///
+    /// Requirements:
+    /// - The list must be finalized.
+    /// - `from_index` must be the `next_index` of the distribution of the caller, or 0. A
+    /// distribution in progress is only restarted by its caller or the list owner.
+    /// - `limit` must be positive.
+    #[payable]
+    pub fn distribute_near_from_list(
+        &mut self,
+        list_id: u64,
+        from_index: u64,
+        limit: u64,
+        options: Option<DistributeOptions>,
+    ) -> Option<u64> {
+        let caller = env::predecessor_account_id();
+        let (receivers, amount) = self.take_list_chunk(list_id, &caller, from_index, limit);
+        self.internal_distribute_near(receivers, amount, options.unwrap_or_default())
+    }
+
+    /// Return the summary of `list_id`.
+    pub fn get_list(&self, list_id: u64) -> Option<RecipientListView> {
+        self.lists.get(&list_id).map(|list| RecipientListView {
+            owner_id: list.owner_id,
+            len: list.entries.len(),
+            total_amount: list.total_amount.into(),
+            finalized: list.finalized,
+            next_index: list.next_index,
+            distributor_id: list.distributor_id,
+        })
+    }
+
+    /// Return the receivers of `list_id`, paginated.
+    pub fn get_list_entries(
+        &self,
+        list_id: u64,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<ListEntry> {
+        let list = self.lists.get(&list_id).expect("List not found");
+        list.entries
+            .iter()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .collect()
+    }
+
+    /// Return the receivers and amounts of the finalized `list_id`.
+    pub(crate) fn finalized_list_entries(&self, list_id: u64) -> (Vec<AccountId>, Vec<U128>) {
+        let list = self.lists.get(&list_id).expect("List not found");
+        assert!(list.finalized, "List is not finalized");
+        list.entries
+            .iter()
+            .map(|entry| (entry.account_id, entry.amount))
+            .unzip()
+    }
+
+    /// Return the receivers and amounts of the `limit` entries of the finalized `list_id` from
+    /// `from_index` and store the progress of the distribution run by `distributor_id`.
+    pub(crate) fn take_list_chunk(
+        &mut self,
+        list_id: u64,
+        distributor_id: &AccountId,
+        from_index: u64,
+        limit: u64,
+    ) -> (Vec<AccountId>, Vec<U128>) {
+        let mut list = self.lists.get(&list_id).expect("List not found");
+        assert!(list.finalized, "List is not finalized");
+        assert!(limit > 0, "limit must be positive");
+        if from_index > 0 {
+            assert!(
+                from_index == list.next_index
+                    && list.distributor_id.as_ref() == Some(distributor_id),
+                "The distribution of the caller does not continue at {}",
+                from_index
+            );
+        } else if let Some(current_id) = list.distributor_id.as_ref() {
+            assert!(
+                current_id == distributor_id || &list.owner_id == distributor_id,
+                "A distribution of the list by {} is in progress",
+                current_id
+            );
+        }
+        let len = list.entries.len();
+        let end = from_index.saturating_add(limit).min(len);
+        let chunk = (from_index..end)
+            .map(|index| {
+                let entry = list.entries.get(index).unwrap();
+                (entry.account_id, entry.amount)
+            })
+            .unzip();
+
+        let initial_storage_usage = env::storage_usage();
+        if end < len {
+            list.next_index = end;
+            list.distributor_id = Some(distributor_id.clone());
+        } else {
+            list.next_index = 0;
+            list.distributor_id = None;
+        }
+        self.lists.insert(&list_id, &list);
+        self.record_storage(distributor_id, initial_storage_usage);
+        chunk
+    }
+
+    fn assert_list_owner(&self, list_id: u64) -> RecipientList {
+        let list = self.lists.get(&list_id).expect("List not found");
+        assert_eq!(
+            env::predecessor_account_id(),
+            list.owner_id,
+            "Only the list owner can change it"
+        );
+        list
+    }
+}
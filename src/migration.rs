@@ -0,0 +1,109 @@
+use crate::*;
+
+/// Version of the state layout written by this code.
+pub const STATE_VERSION: u32 = 3;
+/// Storage key of the state version. States written before versioning have none and are
+/// version 1 or 2, told apart by their layout.
+pub(crate) const STATE_VERSION_KEY: &[u8] = b"STATE_VERSION";
+/// Storage key of the contract state used by `env::state_read`.
+const STATE_KEY: &[u8] = b"STATE";
+
+/// State layout of version 1, the first deployed contract.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV1 {
+    pub owner_id: AccountId,  // admin Id
+    pub oracle_id: AccountId, // oracleId
+    pub balances: LookupMap<AccountId, u128>,
+    pub account_storage_usage: StorageUsage,
+    pub service_fee: U128,
+}
+
+/// State layout of version 2, the contract deployed before versioning.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV2 {
+    pub owner_id: AccountId,
+    pub balances: LookupMap<AccountId, u128>,
+    pub oracle_account_id: AccountId,
+    pub oracle_provider_id: AccountId,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Migrate the state of any deployed version to `STATE_VERSION` after the code is upgraded,
+    /// applying the upgrade steps in order, and set the oracle mode if given.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner, or the contract itself from `deploy_staged_code`.
+    /// - `oracle_mode` is required to migrate from version 1.
+    ///
+    /// Arguments:
+    /// - `oracle_mode`: how the service fee is priced, the current mode is kept if `None`.
+    #[init(ignore_state)]
+    pub fn migrate(oracle_mode: Option<OracleMode>) -> Self {
+        // a new version adds its step here and chains it after the steps of older versions
+        let mut this = match read_state_version() {
+            1 => Self::migrate_v1(
+                env::state_read().expect("failed"),
+                oracle_mode
+                    .clone()
+                    .expect("oracle_mode is required to migrate from version 1"),
+            ),
+            2 => Self::migrate_v2(env::state_read().expect("failed")),
+            STATE_VERSION => env::state_read().expect("failed"),
+            version => env::panic_str(&format!("Unknown state version {}", version)),
+        };
+
+        // Check the caller is authorized to update the code
+        let caller_id = env::predecessor_account_id();
+        assert!(
+            caller_id == this.owner_id || caller_id == env::current_account_id(),
+            "Only owner can update the code"
+        );
+        if let Some(oracle_mode) = oracle_mode {
+            this.oracle_mode = oracle_mode;
+        }
+        write_state_version();
+        this
+    }
+
+    /// Return the version of the state layout.
+    pub fn state_version(&self) -> u32 {
+        read_state_version()
+    }
+
+    /// Upgrade step from version 1: keep the owner and quota balances, everything else starts
+    /// empty. Version 1 holds no NEAR for users, so `held_near` starts at 0.
+    fn migrate_v1(old_state: ContractV1, oracle_mode: OracleMode) -> Self {
+        let mut this = Self::init(oracle_mode);
+        this.owner_id = old_state.owner_id;
+        this.balances = old_state.balances;
+        this
+    }
+
+    /// Upgrade step from version 2: keep the owner, quota balances and oracle, everything else
+    /// starts empty. Version 2 holds no NEAR for users, so `held_near` starts at 0.
+    fn migrate_v2(old_state: ContractV2) -> Self {
+        let mut this = Self::init(OracleMode::Oracle(FluxSource {
+            oracle_account_id: old_state.oracle_account_id,
+            provider_id: old_state.oracle_provider_id,
+        }));
+        this.owner_id = old_state.owner_id;
+        this.balances = old_state.balances;
+        this
+    }
+}
+
+fn read_state_version() -> u32 {
+    match env::storage_read(STATE_VERSION_KEY) {
+        Some(version) => u32::try_from_slice(&version).expect("failed"),
+        // both unversioned layouts are complete borsh values, only one of them reads the state
+        None => match env::storage_read(STATE_KEY) {
+            Some(state) if ContractV2::try_from_slice(&state).is_ok() => 2,
+            _ => 1,
+        },
+    }
+}
+
+pub(crate) fn write_state_version() {
+    env::storage_write(STATE_VERSION_KEY, &STATE_VERSION.try_to_vec().unwrap());
+}
@@ -0,0 +1,346 @@
+use crate::*;
+
+const GAS_FOR_GET_ENTRY: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_GET_PRICE_DATA: Gas = Gas(5_000_000_000_000);
+/// Gas to decode and combine the result of one source in the callback of `query_near_price`.
+const GAS_FOR_DECODE_PRICE: Gas = Gas(2_000_000_000_000);
+/// Decimals of wNEAR, included in the decimals of `get_price_data` prices.
+const NEAR_DECIMALS: u32 = 24;
+/// Decimals all source prices are converted to before they are combined.
+pub const PRICE_DECIMALS: u32 = 8;
+
+/// Query and decoding of the NEAR/USD price of one oracle API.
+pub trait PriceAdapter {
+    /// Query the NEAR/USD price.
+    fn query(&self) -> Promise;
+    /// Decode the result of `query`, `None` if it holds no valid price.
+    fn decode(&self, result: &[u8]) -> Option<OracleEntry>;
+}
+
+/// The `get_entry` entry of `provider_id` on the Flux contract `oracle_account_id`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FluxSource {
+    pub oracle_account_id: AccountId,
+    pub provider_id: AccountId,
+}
+
+/// The `get_price_data` price of `asset_id` on a NEAR price oracle contract such as
+/// `priceoracle.near`. `asset_id` is the wNEAR contract, its price is per yoctoNEAR.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceOracleSource {
+    pub oracle_account_id: AccountId,
+    pub asset_id: AccountId,
+}
+
+/// Price of an asset returned by `get_price_data`, `multiplier / 10^decimals` USD per unit.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetPrice {
+    pub multiplier: U128,
+    pub decimals: u8,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AssetOptionalPrice {
+    pub asset_id: AccountId,
+    pub price: Option<AssetPrice>,
+}
+
+/// Result of `get_price_data`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceData {
+    pub timestamp: U64,
+    pub recency_duration_sec: u32,
+    pub prices: Vec<AssetOptionalPrice>,
+}
+
+/// How the service fee is priced.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum OracleMode {
+    /// Convert USD prices at the NEAR/USD price of this Flux entry and of the additional sources.
+    Oracle(FluxSource),
+    /// Charge `yocto_per_address` per address without querying any oracle, for localnet,
+    /// sandbox tests and private deployments. Subscriptions and redemptions need an oracle.
+    FixedPrice { yocto_per_address: U128 },
+}
+
+/// A NEAR/USD price source queried in addition to the Flux entry of the oracle mode, with the
+/// oracle API it uses.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum PriceSource {
+    Flux(FluxSource),
+    PriceOracle(PriceOracleSource),
+}
+
+impl PriceSource {
+    pub fn adapter(&self) -> &dyn PriceAdapter {
+        match self {
+            PriceSource::Flux(source) => source,
+            PriceSource::PriceOracle(source) => source,
+        }
+    }
+}
+
+impl PriceAdapter for FluxSource {
+    fn query(&self) -> Promise {
+        Promise::new(self.oracle_account_id.clone()).function_call(
+            "get_entry".to_string(),
+            serde_json::to_vec(&json!({
+                "pair": "NEAR/USD".to_string(),
+                "provider": self.provider_id.to_string()
+            }))
+            .unwrap(),
+            NO_DEPOSIT,
+            GAS_FOR_GET_ENTRY,
+        )
+    }
+
+    fn decode(&self, result: &[u8]) -> Option<OracleEntry> {
+        serde_json::from_slice::<OracleEntry>(result).ok()
+    }
+}
+
+impl PriceAdapter for PriceOracleSource {
+    fn query(&self) -> Promise {
+        Promise::new(self.oracle_account_id.clone()).function_call(
+            "get_price_data".to_string(),
+            serde_json::to_vec(&json!({ "asset_ids": [self.asset_id] })).unwrap(),
+            NO_DEPOSIT,
+            GAS_FOR_GET_PRICE_DATA,
+        )
+    }
+
+    fn decode(&self, result: &[u8]) -> Option<OracleEntry> {
+        let data = serde_json::from_slice::<PriceData>(result).ok()?;
+        let price = data
+            .prices
+            .into_iter()
+            .find(|price| price.asset_id == self.asset_id)?
+            .price?;
+        // the price is per yoctoNEAR, per NEAR it has 24 decimals less
+        let decimals = (price.decimals as u32).checked_sub(NEAR_DECIMALS)?;
+        Some(OracleEntry {
+            price: price.multiplier,
+            decimals,
+            last_update: data.timestamp.0,
+        })
+    }
+}
+
+/// How the prices of all sources are combined.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OracleConfig {
+    /// Sources queried in parallel with the Flux entry of the oracle mode.
+    pub sources: Vec<PriceSource>,
+    /// Number of prices within `max_deviation_bps` of the median needed to price a payment.
+    pub min_quorum: u8,
+    /// Maximum deviation of a price from the median in basis points, others are ignored.
+    pub max_deviation_bps: u16,
+}
+
+impl Default for OracleConfig {
+    fn default() -> Self {
+        Self {
+            sources: Vec::new(),
+            min_quorum: 1,
+            max_deviation_bps: MAX_BASIS_POINTS,
+        }
+    }
+}
+
+impl OracleEntry {
+    /// The price with `PRICE_DECIMALS` decimals, rounded down. `None` if it overflows.
+    fn normalized_price(&self) -> Option<u128> {
+        if self.decimals >= PRICE_DECIMALS {
+            Some(
+                10u128
+                    .checked_pow(self.decimals - PRICE_DECIMALS)
+                    .map_or(0, |scale| self.price.0 / scale),
+            )
+        } else {
+            10u128
+                .checked_pow(PRICE_DECIMALS - self.decimals)
+                .and_then(|scale| self.price.0.checked_mul(scale))
+        }
+    }
+
+    /// Convert `usd_value`, expressed with `usd_decimals` decimals, to yoctoNEAR at this NEAR/USD price.
+    ///
+    /// Panics if the price is zero or the conversion overflows.
+    pub fn usd_to_yocto(&self, usd_value: u128, usd_decimals: u32) -> Balance {
+        assert!(self.price.0 > 0, "Oracle price must be positive");
+        let overflow = "USD value is too large to convert to yoctoNEAR";
+        let yocto_usd = usd_value.checked_mul(ONE_NEAR).expect(overflow);
+        if self.decimals >= usd_decimals {
+            10u128
+                .checked_pow(self.decimals - usd_decimals)
+                .and_then(|scale| yocto_usd.checked_mul(scale))
+                .expect(overflow)
+                / self.price.0
+        } else {
+            10u128
+                .checked_pow(usd_decimals - self.decimals)
+                .map_or(0, |scale| yocto_usd / scale)
+                / self.price.0
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Set the additional price sources and how prices are combined. Failed sources and prices
+    /// deviating more than `max_deviation_bps` from the median are ignored, the median of the
+    /// remaining ones is used if there are at least `min_quorum`.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner.
+    /// - `min_quorum` must be positive and not exceed the number of sources.
+    pub fn set_oracle_config(&mut self, config: OracleConfig) {
+        self.assert_owner();
+        assert!(
+            config.min_quorum > 0 && config.min_quorum as usize <= config.sources.len() + 1,
+            "min_quorum must be between 1 and the number of sources"
+        );
+        assert!(
+            config.max_deviation_bps <= MAX_BASIS_POINTS,
+            "max deviation must not exceed {} basis points",
+            MAX_BASIS_POINTS
+        );
+        self.oracle_config = config;
+    }
+
+    /// Return the additional price sources and how prices are combined.
+    pub fn oracle_config(&self) -> OracleConfig {
+        self.oracle_config.clone()
+    }
+
+    /// Switch between oracle pricing and a fixed price per address.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner.
+    /// - A fixed price must be positive.
+    pub fn set_oracle_mode(&mut self, oracle_mode: OracleMode) {
+        self.assert_owner();
+        if let OracleMode::FixedPrice { yocto_per_address } = &oracle_mode {
+            assert!(yocto_per_address.0 > 0, "fixed price must be positive");
+        }
+        self.oracle_mode = oracle_mode;
+    }
+
+    /// Return how the service fee is priced.
+    pub fn oracle_mode(&self) -> OracleMode {
+        self.oracle_mode.clone()
+    }
+}
+
+impl Contract {
+    /// Query the NEAR/USD entry of the oracle mode and of every additional source in parallel.
+    ///
+    /// Requirements:
+    /// - The contract must be in oracle mode.
+    pub(crate) fn query_near_price(&self) -> Promise {
+        let primary = self
+            .primary_source()
+            .expect("No oracle is used in fixed price mode");
+        self.oracle_config
+            .sources
+            .iter()
+            .fold(primary.query(), |promise, source| {
+                promise.and(source.adapter().query())
+            })
+    }
+
+    /// Gas for a callback of `query_near_price` that needs `base` besides decoding the results.
+    pub(crate) fn price_callback_gas(&self, base: Gas) -> Gas {
+        base + GAS_FOR_DECODE_PRICE * (self.oracle_config.sources.len() as u64 + 1)
+    }
+
+    /// Combine the entries returned by `query_near_price` in its callback. Return `None` if not
+    /// enough sources agree on the price.
+    pub(crate) fn near_price_result(&self) -> Option<OracleEntry> {
+        let count = env::promise_results_count();
+        if count as usize != self.oracle_config.sources.len() + 1 {
+            // the sources changed while they were queried
+            return None;
+        }
+        let primary = self.primary_source()?;
+        let adapters = std::iter::once(primary as &dyn PriceAdapter).chain(
+            self.oracle_config
+                .sources
+                .iter()
+                .map(|source| source.adapter()),
+        );
+        let entries = adapters
+            .enumerate()
+            .filter_map(|(i, adapter)| match env::promise_result(i as u64) {
+                PromiseResult::Successful(result) => adapter.decode(&result),
+                _ => None,
+            })
+            .collect();
+        aggregate_prices(
+            entries,
+            self.oracle_config.min_quorum,
+            self.oracle_config.max_deviation_bps,
+        )
+    }
+
+    fn primary_source(&self) -> Option<&FluxSource> {
+        match &self.oracle_mode {
+            OracleMode::Oracle(source) => Some(source),
+            OracleMode::FixedPrice { .. } => None,
+        }
+    }
+}
+
+/// Return the median of the prices within `max_deviation_bps` of the median of all `entries`,
+/// with `PRICE_DECIMALS` decimals. Prices that cannot be represented with those decimals are
+/// ignored. `None` if fewer than `min_quorum` prices remain.
+pub(crate) fn aggregate_prices(
+    entries: Vec<OracleEntry>,
+    min_quorum: u8,
+    max_deviation_bps: u16,
+) -> Option<OracleEntry> {
+    let last_update = entries.iter().map(|e| e.last_update).max()?;
+    let prices: Vec<u128> = entries
+        .iter()
+        .filter_map(|e| e.normalized_price())
+        .filter(|price| *price > 0)
+        .collect();
+    if prices.is_empty() {
+        return None;
+    }
+    let median_price = median(prices.clone());
+    // split the product so it can't overflow, `max_deviation_bps` is at most `MAX_BASIS_POINTS`
+    let bps = max_deviation_bps as u128;
+    let max_deviation = median_price / MAX_BASIS_POINTS as u128 * bps
+        + median_price % MAX_BASIS_POINTS as u128 * bps / MAX_BASIS_POINTS as u128;
+    let prices: Vec<u128> = prices
+        .into_iter()
+        .filter(|price| price.abs_diff(median_price) <= max_deviation)
+        .collect();
+    if prices.is_empty() || prices.len() < min_quorum as usize {
+        return None;
+    }
+    Some(OracleEntry {
+        price: median(prices).into(),
+        decimals: PRICE_DECIMALS,
+        last_update,
+    })
+}
+
+fn median(mut prices: Vec<u128>) -> u128 {
+    prices.sort_unstable();
+    // both middle prices are the same one for an odd count
+    let lower = prices[(prices.len() - 1) / 2];
+    let upper = prices[prices.len() / 2];
+    lower + (upper - lower) / 2
+}
@@ -0,0 +1,138 @@
+use crate::*;
+
+/// Features of the contract that can be paused independently.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug,
+)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    DistributeNear,
+    DistributeFt,
+    StorageDeposit,
+    PayFee,
+}
+
+impl Feature {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Feature::DistributeNear => "distribute_near",
+            Feature::DistributeFt => "distribute_ft",
+            Feature::StorageDeposit => "storage_deposit",
+            Feature::PayFee => "pay_fee",
+        }
+    }
+}
+
+/// Pause flag of every feature. A paused feature rejects new calls to its entry points.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Default, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PauseStatus {
+    pub distribute_near: bool,
+    pub distribute_ft: bool,
+    pub storage_deposit: bool,
+    pub pay_fee: bool,
+}
+
+impl PauseStatus {
+    pub fn get(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::DistributeNear => self.distribute_near,
+            Feature::DistributeFt => self.distribute_ft,
+            Feature::StorageDeposit => self.storage_deposit,
+            Feature::PayFee => self.pay_fee,
+        }
+    }
+
+    fn set(&mut self, feature: Feature, paused: bool) {
+        match feature {
+            Feature::DistributeNear => self.distribute_near = paused,
+            Feature::DistributeFt => self.distribute_ft = paused,
+            Feature::StorageDeposit => self.storage_deposit = paused,
+            Feature::PayFee => self.pay_fee = paused,
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Grant `account_id` the permission to pause features.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner.
+    pub fn add_pauser(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.pauser_ids.insert(&account_id);
+    }
+
+    /// Revoke the permission to pause features from `account_id`.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner.
+    pub fn remove_pauser(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.pauser_ids.remove(&account_id);
+    }
+
+    /// Pause `feature`. While paused, its entry points fail fast, except `ft_on_transfer`
+    /// which returns the full amount so the tokens are refunded to the sender.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner or a pauser.
+    pub fn pause(&mut self, feature: Feature) {
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == self.owner_id || self.pauser_ids.contains(&caller),
+            "only contract owner or pauser can pause"
+        );
+        self.set_paused(feature, true);
+    }
+
+    /// Resume `feature`.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner. Pausers can only pause so that a leaked
+    /// pauser key cannot re-open a feature the owner stopped.
+    pub fn unpause(&mut self, feature: Feature) {
+        self.assert_owner();
+        self.set_paused(feature, false);
+    }
+
+    /// Return the pause flag of every feature.
+    pub fn paused_features(&self) -> PauseStatus {
+        self.paused.clone()
+    }
+
+    /// Return whether `feature` is paused.
+    pub fn is_paused(&self, feature: Feature) -> bool {
+        self.paused.get(feature)
+    }
+
+    /// Return all accounts allowed to pause features.
+    pub fn pausers(&self) -> Vec<AccountId> {
+        self.pauser_ids.to_vec()
+    }
+
+    fn set_paused(&mut self, feature: Feature, paused: bool) {
+        if self.paused.get(feature) == paused {
+            return;
+        }
+        self.paused.set(feature, paused);
+        let pause_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::SetPaused(SetPausedLog {
+                feature: feature.as_str().to_string(),
+                paused,
+                account_id: env::predecessor_account_id().to_string(),
+            }),
+        };
+        env::log_str(&pause_log.to_string());
+    }
+
+    pub(crate) fn assert_not_paused(&self, feature: Feature) {
+        if self.paused.get(feature) {
+            env::panic_str(&format!("{} is paused", feature.as_str()));
+        }
+    }
+}
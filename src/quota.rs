@@ -0,0 +1,153 @@
+use crate::*;
+
+/// Quota taken from `owner_id` for a batch sent by `spender_id`. It is passed to the
+/// distribution callbacks so quota of failed transfers goes back where it came from.
+/// Of the `amount` addresses, `from_subscription` were covered by the owner's subscription and
+/// `from_balance` by its per-address quota. Fee-exempt owners are charged neither.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct QuotaCharge {
+    pub owner_id: AccountId,
+    pub spender_id: AccountId,
+    pub amount: u128,
+    pub from_subscription: u128,
+    pub from_balance: u128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Transfer `amount` of quota from `env::predecessor_account_id` to `receiver_id`.
+    pub fn transfer_quota(&mut self, receiver_id: AccountId, amount: u128) {
+        let initial_storage_usage = env::storage_usage();
+        let sender_id = env::predecessor_account_id();
+        assert!(amount > 0, "amount must be positive");
+        assert_ne!(sender_id, receiver_id, "Cannot transfer quota to yourself");
+        assert!(
+            self.get_account_quota(&sender_id) >= amount,
+            "Not enough quota for user"
+        );
+        self.decrease_account_quota(&sender_id, amount);
+        self.increase_account_quota(&receiver_id, amount);
+        self.charge_storage(&sender_id, initial_storage_usage);
+
+        let transfer_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::TransferQuota(TransferQuotaLog {
+                amount: amount.to_string(),
+                sender_id: sender_id.to_string(),
+                receiver_id: receiver_id.to_string(),
+            }),
+        };
+        env::log_str(&transfer_log.to_string());
+    }
+
+    /// Allow `spender_id` to send distributions paid with up to `amount` of the quota of
+    /// `env::predecessor_account_id`. The allowance replaces any previous one, `0` revokes it.
+    pub fn approve_quota_spender(&mut self, spender_id: AccountId, amount: u128) {
+        let initial_storage_usage = env::storage_usage();
+        let owner_id = env::predecessor_account_id();
+        assert_ne!(owner_id, spender_id, "Cannot approve yourself");
+        let key = (owner_id.clone(), spender_id.clone());
+        if amount == 0 {
+            self.quota_allowances.remove(&key);
+        } else {
+            self.quota_allowances.insert(&key, &amount);
+        }
+        self.charge_storage(&owner_id, initial_storage_usage);
+
+        let approve_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::ApproveQuotaSpender(ApproveQuotaSpenderLog {
+                amount: amount.to_string(),
+                owner_id: owner_id.to_string(),
+                spender_id: spender_id.to_string(),
+            }),
+        };
+        env::log_str(&approve_log.to_string());
+    }
+
+    /// Return the quota of `owner_id` that `spender_id` is still allowed to use.
+    pub fn quota_allowance(&self, owner_id: AccountId, spender_id: AccountId) -> u128 {
+        self.quota_allowances
+            .get(&(owner_id, spender_id))
+            .unwrap_or_default()
+    }
+
+    /// Take `amount` of quota for a batch sent by `spender_id`. The quota of `owner_id` is used
+    /// when given, which requires an allowance from the owner to the spender. An active
+    /// subscription of the owner is used before its per-address quota. Fee-exempt owners only
+    /// need the allowance.
+    pub(crate) fn charge_quota(
+        &mut self,
+        owner_id: Option<AccountId>,
+        spender_id: AccountId,
+        amount: u128,
+    ) -> QuotaCharge {
+        let owner_id = owner_id.unwrap_or_else(|| spender_id.clone());
+        if owner_id != spender_id {
+            let key = (owner_id.clone(), spender_id.clone());
+            let allowance = self.quota_allowances.get(&key).unwrap_or_default();
+            assert!(allowance >= amount, "Not enough quota allowance");
+            self.quota_allowances.insert(&key, &(allowance - amount));
+        }
+        if self.is_fee_exempt(&owner_id) {
+            return QuotaCharge {
+                owner_id,
+                spender_id,
+                amount,
+                from_subscription: 0,
+                from_balance: 0,
+            };
+        }
+        let from_subscription = self.use_subscription(&owner_id, amount);
+        let from_balance = amount - from_subscription;
+        assert!(
+            self.get_account_quota(&owner_id) >= from_balance,
+            "Not enough quota for user"
+        );
+        self.decrease_account_quota(&owner_id, from_balance);
+        QuotaCharge {
+            owner_id,
+            spender_id,
+            amount,
+            from_subscription,
+            from_balance,
+        }
+    }
+
+    /// Return the quota `account_id` can use now, including its active subscription, or `None`
+    /// if it is exempt from fees.
+    pub(crate) fn available_quota(&self, account_id: &AccountId) -> Option<u128> {
+        if self.is_fee_exempt(account_id) {
+            return None;
+        }
+        let from_subscription = match self.subscriptions.get(account_id) {
+            Some(subscription) if subscription.is_active() => subscription.remaining(),
+            _ => 0,
+        };
+        Some(from_subscription.saturating_add(self.get_account_quota(account_id)))
+    }
+
+    /// Give back `amount` of quota taken by `charge`, restoring the allowance it consumed.
+    /// Per-address quota is given back before subscription addresses.
+    pub(crate) fn refund_quota(&mut self, charge: &QuotaCharge, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        let to_balance = amount.min(charge.from_balance);
+        let to_subscription = (amount - to_balance).min(charge.from_subscription);
+        if to_balance > 0 {
+            self.increase_account_quota(&charge.owner_id, to_balance);
+        }
+        if to_subscription > 0 {
+            self.release_subscription(&charge.owner_id, to_subscription);
+        }
+        if charge.owner_id != charge.spender_id {
+            let key = (charge.owner_id.clone(), charge.spender_id.clone());
+            let allowance = self.quota_allowances.get(&key).unwrap_or_default();
+            self.quota_allowances.insert(&key, &(allowance + amount));
+        }
+    }
+}
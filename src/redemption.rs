@@ -0,0 +1,202 @@
+use crate::*;
+
+const GAS_FOR_REDEEM_QUOTA_CALLBACK: Gas = Gas(10_000_000_000_000);
+
+/// Quota bought in one `pay_service_fee` payment that has not been used yet.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct QuotaLot {
+    pub remaining: u128,
+    /// yoctoNEAR paid per address, net of the referral share.
+    pub price_per_address: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Set the fee kept on every redemption, in basis points of the refunded amount.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner.
+    /// - `redemption_fee_bps` must not exceed 10000.
+    pub fn set_redemption_fee(&mut self, redemption_fee_bps: u16) {
+        self.assert_owner();
+        assert!(
+            redemption_fee_bps <= MAX_BASIS_POINTS,
+            "redemption fee must not exceed {} basis points",
+            MAX_BASIS_POINTS
+        );
+        self.redemption_fee_bps = redemption_fee_bps;
+    }
+
+    /// Return the redemption fee in basis points.
+    pub fn redemption_fee(&self) -> u16 {
+        self.redemption_fee_bps
+    }
+
+    /// Burn `amount` of quota of `env::predecessor_account_id` and refund NEAR for it. Each address
+    /// is refunded at the lower of the price paid for it, net of the referral share, and the
+    /// current oracle price, or the fixed price in fixed price mode, minus the redemption fee.
+    /// Only purchased quota can be redeemed, the oldest purchases first.
+    pub fn redeem_quota(&mut self, amount: u128) -> PromiseOrValue<()> {
+        self.assert_not_paused(Feature::PayFee);
+        assert!(amount > 0, "amount must be positive");
+        let account_id = env::predecessor_account_id();
+        assert!(
+            self.redeemable_quota(account_id.clone()) >= amount,
+            "Not enough redeemable quota"
+        );
+        let lots = self.take_quota_lots(&account_id, amount);
+        self.decrease_account_quota(&account_id, amount);
+        if let OracleMode::FixedPrice { yocto_per_address } = self.oracle_mode {
+            self.redeem_quota_lots(account_id, amount, lots, yocto_per_address.0);
+            return PromiseOrValue::Value(());
+        }
+        self.query_near_price()
+            .then(ext_self::callback_redeem_quota(
+                account_id,
+                amount,
+                lots,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                self.price_callback_gas(GAS_FOR_REDEEM_QUOTA_CALLBACK),
+            ))
+            .into()
+    }
+
+    #[private]
+    pub fn callback_redeem_quota(
+        &mut self,
+        account_id: AccountId,
+        amount: u128,
+        lots: Vec<QuotaLot>,
+    ) {
+        let oracle_fee = match self.near_price_result() {
+            Some(entry) => entry.usd_to_yocto(
+                FEE_USD_PER_ADDRESS_VALUE,
+                FEE_USD_PER_ADDRESS_DECIMAL_OFFSET,
+            ),
+            None => {
+                log!("price query failed, quota of {} restored", account_id);
+                return self.restore_quota_lots(&account_id, amount, lots);
+            }
+        };
+        self.redeem_quota_lots(account_id, amount, lots, oracle_fee);
+    }
+
+    /// Return the quota of `account_id` that can be redeemed.
+    pub fn redeemable_quota(&self, account_id: AccountId) -> u128 {
+        self.quota_lots
+            .get(&account_id)
+            .unwrap_or_default()
+            .iter()
+            .map(|lot| lot.remaining)
+            .sum()
+    }
+
+    /// Return the unused purchases of `account_id`, oldest first.
+    pub fn quota_lots_of(&self, account_id: AccountId) -> Vec<QuotaLot> {
+        self.quota_lots.get(&account_id).unwrap_or_default()
+    }
+
+    pub(crate) fn add_quota_lot(
+        &mut self,
+        account_id: &AccountId,
+        amount: u128,
+        price_per_address: Balance,
+    ) {
+        if amount == 0 {
+            return;
+        }
+        let mut lots = self.quota_lots.get(account_id).unwrap_or_default();
+        lots.push(QuotaLot {
+            remaining: amount,
+            price_per_address: price_per_address.into(),
+        });
+        self.quota_lots.insert(account_id, &lots);
+    }
+
+    /// Drop the oldest lots of `account_id` until they add up to at most `quota`, so quota
+    /// spent or transferred can no longer be redeemed.
+    pub(crate) fn trim_quota_lots(&mut self, account_id: &AccountId, quota: u128) {
+        let lots = match self.quota_lots.get(account_id) {
+            Some(lots) => lots,
+            None => return,
+        };
+        let total: u128 = lots.iter().map(|lot| lot.remaining).sum();
+        if total > quota {
+            self.take_quota_lots(account_id, total - quota);
+        }
+    }
+
+    /// Refund the taken `lots` of `amount` quota at most at `price_per_address`, or restore them if
+    /// the collected fees cannot cover the refund.
+    fn redeem_quota_lots(
+        &mut self,
+        account_id: AccountId,
+        amount: u128,
+        lots: Vec<QuotaLot>,
+        price_per_address: Balance,
+    ) {
+        let refund: Balance = lots
+            .iter()
+            .map(|lot| lot.remaining * lot.price_per_address.0.min(price_per_address))
+            .sum();
+        let fee = refund * self.redemption_fee_bps as u128 / MAX_BASIS_POINTS as u128;
+        let payout = refund - fee;
+        if payout > self.collected_fees {
+            log!(
+                "not enough collected fees, quota of {} restored",
+                account_id
+            );
+            return self.restore_quota_lots(&account_id, amount, lots);
+        }
+        self.collected_fees -= payout;
+        if payout > 0 {
+            Promise::new(account_id.clone()).transfer(payout);
+        }
+
+        let redeem_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::RedeemQuota(RedeemQuotaLog {
+                amount: amount.to_string(),
+                refund: payout.to_string(),
+                fee: fee.to_string(),
+                user_id: account_id.to_string(),
+            }),
+        };
+        env::log_str(&redeem_log.to_string());
+    }
+
+    /// Remove `amount` of quota from the oldest lots of `account_id` and return what was removed.
+    fn take_quota_lots(&mut self, account_id: &AccountId, amount: u128) -> Vec<QuotaLot> {
+        let mut lots = self.quota_lots.get(account_id).unwrap_or_default();
+        let mut taken: Vec<QuotaLot> = Vec::new();
+        let mut left = amount;
+        while left > 0 && !lots.is_empty() {
+            let used = left.min(lots[0].remaining);
+            taken.push(QuotaLot {
+                remaining: used,
+                price_per_address: lots[0].price_per_address,
+            });
+            lots[0].remaining -= used;
+            if lots[0].remaining == 0 {
+                lots.remove(0);
+            }
+            left -= used;
+        }
+        if lots.is_empty() {
+            self.quota_lots.remove(account_id);
+        } else {
+            self.quota_lots.insert(account_id, &lots);
+        }
+        taken
+    }
+
+    fn restore_quota_lots(&mut self, account_id: &AccountId, amount: u128, taken: Vec<QuotaLot>) {
+        self.increase_account_quota(account_id, amount);
+        let mut lots = taken;
+        lots.extend(self.quota_lots.get(account_id).unwrap_or_default());
+        self.quota_lots.insert(account_id, &lots);
+    }
+}
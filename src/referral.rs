@@ -0,0 +1,135 @@
+use crate::*;
+
+pub const MAX_BASIS_POINTS: u16 = 10_000;
+const GAS_FOR_CLAIM_REFERRAL_CALLBACK: Gas = Gas(5_000_000_000_000);
+
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct ReferralAccount {
+    pub claimable: Balance,
+    pub total_earned: Balance,
+    pub referred_payments: u64,
+}
+
+/// Referral rewards of an account.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReferralStats {
+    /// Rewards that can be claimed with `claim_referral_rewards`.
+    pub claimable: U128,
+    /// Total rewards credited to the account, claimed or not.
+    pub total_earned: U128,
+    /// Number of fee payments that credited the account.
+    pub referred_payments: u64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Set the share of every referred fee payment credited to the referrer, in basis points.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner.
+    /// - `referral_fee_bps` must not exceed 10000.
+    pub fn set_referral_fee(&mut self, referral_fee_bps: u16) {
+        self.assert_owner();
+        assert!(
+            referral_fee_bps <= MAX_BASIS_POINTS,
+            "referral fee must not exceed {} basis points",
+            MAX_BASIS_POINTS
+        );
+        self.referral_fee_bps = referral_fee_bps;
+    }
+
+    /// Return the referral share in basis points.
+    pub fn referral_fee(&self) -> u16 {
+        self.referral_fee_bps
+    }
+
+    /// Transfer all claimable referral rewards of `env::predecessor_account_id` to it.
+    pub fn claim_referral_rewards(&mut self) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let mut referral = self.referrals.get(&account_id).unwrap_or_default();
+        let amount = referral.claimable;
+        assert!(amount > 0, "No referral rewards to claim");
+        referral.claimable = 0;
+        self.referrals.insert(&account_id, &referral);
+        self.release_held_near(amount);
+
+        let claim_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::ClaimReferralRewards(ClaimReferralRewardsLog {
+                amount: amount.to_string(),
+                referrer_id: account_id.to_string(),
+            }),
+        };
+        env::log_str(&claim_log.to_string());
+
+        Promise::new(account_id.clone()).transfer(amount).then(
+            ext_self::callback_claim_referral_rewards(
+                account_id,
+                amount.into(),
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_CLAIM_REFERRAL_CALLBACK,
+            ),
+        )
+    }
+
+    #[private]
+    pub fn callback_claim_referral_rewards(&mut self, referrer_id: AccountId, amount: U128) {
+        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
+        if let PromiseResult::Failed = env::promise_result(0) {
+            log!("referral claim of {} for {} failed", amount.0, referrer_id);
+            let mut referral = self.referrals.get(&referrer_id).unwrap_or_default();
+            referral.claimable += amount.0;
+            self.referrals.insert(&referrer_id, &referral);
+            self.hold_near(amount.0);
+        }
+    }
+
+    /// Return the referral rewards of `account_id`.
+    pub fn referral_stats(&self, account_id: AccountId) -> ReferralStats {
+        let referral = self.referrals.get(&account_id).unwrap_or_default();
+        ReferralStats {
+            claimable: referral.claimable.into(),
+            total_earned: referral.total_earned.into(),
+            referred_payments: referral.referred_payments,
+        }
+    }
+
+    /// Credit the referral share of `fee` paid by `user_id` to `referrer_id` and
+    /// return the share. Self-referrals are ignored.
+    pub(crate) fn credit_referral(
+        &mut self,
+        referrer_id: Option<AccountId>,
+        user_id: &AccountId,
+        fee: Balance,
+    ) -> Balance {
+        let referrer_id = match referrer_id {
+            Some(referrer_id) if &referrer_id != user_id => referrer_id,
+            _ => return 0,
+        };
+        let share = fee * self.referral_fee_bps as u128 / MAX_BASIS_POINTS as u128;
+        if share == 0 {
+            return 0;
+        }
+        let mut referral = self.referrals.get(&referrer_id).unwrap_or_default();
+        referral.claimable += share;
+        referral.total_earned += share;
+        referral.referred_payments += 1;
+        self.referrals.insert(&referrer_id, &referral);
+        self.hold_near(share);
+
+        let referral_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::ReferralReward(ReferralRewardLog {
+                amount: share.to_string(),
+                referrer_id: referrer_id.to_string(),
+                user_id: user_id.to_string(),
+            }),
+        };
+        env::log_str(&referral_log.to_string());
+        share
+    }
+}
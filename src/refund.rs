@@ -0,0 +1,78 @@
+use crate::*;
+
+const GAS_FOR_REFUND_FUNDS_CALLBACK: Gas = Gas(5_000_000_000_000);
+
+#[near_bindgen]
+impl Contract {
+    /// Send the tokens of `token_id` whose refund to `env::predecessor_account_id` failed, e.g.
+    /// because it was not registered with the token. They stay claimable if the transfer fails.
+    pub fn claim_token_refund(&mut self, token_id: AccountId) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let initial_storage_usage = env::storage_usage();
+        let amount = self
+            .token_refunds
+            .remove(&(account_id.clone(), token_id.clone()))
+            .expect("No tokens to claim");
+        self.record_storage(&account_id, initial_storage_usage);
+        self.refund_token(token_id, &account_id, amount)
+    }
+
+    /// Return the tokens of `token_id` claimable by `account_id` with `claim_token_refund`.
+    pub fn token_refund(&self, account_id: AccountId, token_id: AccountId) -> U128 {
+        self.token_refunds
+            .get(&(account_id, token_id))
+            .unwrap_or(0)
+            .into()
+    }
+
+    /// Keep the tokens of a failed refund claimable with `claim_token_refund`.
+    #[private]
+    pub fn callback_refund_funds(
+        &mut self,
+        token_id: AccountId,
+        account_id: AccountId,
+        amount: U128,
+    ) {
+        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
+        if let PromiseResult::Successful(_) = env::promise_result(0) {
+            return;
+        }
+        log!(
+            "refund of {} {} to {} failed, claim it with claim_token_refund",
+            amount.0,
+            token_id,
+            account_id
+        );
+        let initial_storage_usage = env::storage_usage();
+        let key = (account_id.clone(), token_id);
+        let balance = self.token_refunds.get(&key).unwrap_or(0);
+        self.token_refunds.insert(&key, &(balance + amount.0));
+        self.record_storage(&account_id, initial_storage_usage);
+    }
+
+    /// Send `amount` of `token_id` held by the contract to `account_id`, keeping it claimable if
+    /// the transfer fails.
+    pub(crate) fn refund_token(
+        &self,
+        token_id: AccountId,
+        account_id: &AccountId,
+        amount: Balance,
+    ) -> Promise {
+        ext_ft::ft_transfer(
+            account_id.clone(),
+            amount.into(),
+            Some(String::from("")),
+            token_id.clone(),
+            1, // yocto NEAR to attach
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::callback_refund_funds(
+            token_id,
+            account_id.clone(),
+            amount.into(),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_REFUND_FUNDS_CALLBACK,
+        ))
+    }
+}
@@ -0,0 +1,325 @@
+use crate::*;
+
+const GAS_FOR_EXECUTE_SCHEDULE_CALLBACK: Gas = Gas(15_000_000_000_000);
+/// Gas used by `callback_execute_schedule` for each receiver.
+const GAS_PER_SCHEDULE_RECEIVER: Gas = Gas(1_000_000_000_000);
+/// Storage reserved in the storage balance of the owner for the result record of one period.
+const PERIOD_RESULT_STORAGE: StorageUsage = 150;
+/// Most periods of one schedule, which bounds the storage reserved for its period records.
+pub const MAX_SCHEDULE_PERIODS: u64 = 10_000;
+/// Most receivers of a scheduled list, so a period is paid in one call.
+pub const MAX_SCHEDULE_RECEIVERS: u64 = 50;
+
+/// A recurring distribution of a finalized recipient list, funded in advance by `owner_id`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Schedule {
+    pub owner_id: AccountId,
+    pub list_id: u64,
+    /// Token contract of a NEP-141 schedule, `None` for NEAR.
+    pub token_id: Option<AccountId>,
+    /// Block timestamp in nanoseconds at which the first period is due.
+    pub start_at: U64,
+    /// Time between two periods in nanoseconds.
+    pub interval: U64,
+    /// Block timestamp in nanoseconds after which no period is due.
+    pub end_at: U64,
+    /// Index of the next period to execute.
+    pub next_period: u64,
+    /// Funds left for the coming periods.
+    pub balance: U128,
+    pub cancelled: bool,
+}
+
+impl Schedule {
+    /// Block timestamp in nanoseconds at which `period` is due.
+    pub fn due_at(&self, period: u64) -> u64 {
+        self.start_at.0 + period * self.interval.0
+    }
+
+    pub fn num_periods(&self) -> u64 {
+        (self.end_at.0 - self.start_at.0) / self.interval.0 + 1
+    }
+}
+
+/// The outcome of one executed period.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PeriodResult {
+    pub executed_at: U64,
+    pub succeeded: u64,
+    pub failed: u64,
+    /// Amount of the failed transfers, returned to the schedule balance.
+    pub failed_amount: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// A payable method to pay `list_id` every `interval` from `start_at` until `end_at`. The
+    /// storage of the schedule and of its period records is charged to the storage balance of
+    /// the caller, the attached deposit is added to it first. The schedule is funded with
+    /// `fund_schedule`, or with `ft_transfer_call` and a `{"schedule_id": <id>}` message for a
+    /// token schedule.
+    ///
+    /// Requirements:
+    /// - The caller must own the finalized `list_id`.
+    /// - The list must have at most `MAX_SCHEDULE_RECEIVERS` receivers.
+    /// - `interval` must be positive and `end_at` must not be before `start_at`.
+    /// - The schedule must have at most `MAX_SCHEDULE_PERIODS` periods.
+    ///
+    /// Arguments:
+    /// - `token_id`: the token contract to distribute, `None` for NEAR.
+    /// - `start_at`, `end_at`: block timestamps in nanoseconds.
+    /// - `interval`: the time between two periods in nanoseconds.
+    #[payable]
+    pub fn create_schedule(
+        &mut self,
+        list_id: u64,
+        token_id: Option<AccountId>,
+        start_at: U64,
+        interval: U64,
+        end_at: U64,
+    ) -> u64 {
+        let owner_id = env::predecessor_account_id();
+        let list = self.lists.get(&list_id).expect("List not found");
+        assert_eq!(
+            list.owner_id, owner_id,
+            "Only the list owner can schedule it"
+        );
+        assert!(list.finalized, "List is not finalized");
+        assert!(
+            list.entries.len() <= MAX_SCHEDULE_RECEIVERS,
+            "A scheduled list has at most {} receivers",
+            MAX_SCHEDULE_RECEIVERS
+        );
+        assert!(interval.0 > 0, "interval must be positive");
+        assert!(end_at.0 >= start_at.0, "end_at must not be before start_at");
+        assert!(
+            (end_at.0 - start_at.0) / interval.0 < MAX_SCHEDULE_PERIODS,
+            "A schedule has at most {} periods",
+            MAX_SCHEDULE_PERIODS
+        );
+
+        self.deposit_attached_storage(&owner_id);
+        let initial_storage_usage = env::storage_usage();
+        let schedule_id = self.next_schedule_id;
+        self.next_schedule_id += 1;
+        let schedule = Schedule {
+            owner_id: owner_id.clone(),
+            list_id,
+            token_id,
+            start_at,
+            interval,
+            end_at,
+            next_period: 0,
+            balance: 0.into(),
+            cancelled: false,
+        };
+        self.schedules.insert(&schedule_id, &schedule);
+        self.scheduled_lists.insert(
+            &list_id,
+            &(self.scheduled_lists.get(&list_id).unwrap_or_default() + 1),
+        );
+
+        self.charge_storage(&owner_id, initial_storage_usage);
+        // period records are written by callbacks, which cannot fail on the storage balance
+        self.reserve_storage(&owner_id, period_result_storage(schedule.num_periods()));
+        schedule_id
+    }
+
+    /// A payable method to add the attached deposit to the balance of the NEAR `schedule_id`.
+    #[payable]
+    pub fn fund_schedule(&mut self, schedule_id: u64) {
+        let mut schedule = self
+            .schedules
+            .get(&schedule_id)
+            .expect("Schedule not found");
+        assert!(!schedule.cancelled, "Schedule is cancelled");
+        assert!(schedule.token_id.is_none(), "Schedule distributes a token");
+        schedule.balance = (schedule.balance.0 + env::attached_deposit()).into();
+        self.schedules.insert(&schedule_id, &schedule);
+        self.hold_near(env::attached_deposit());
+    }
+
+    /// Execute the next period of `schedule_id` once it is due. Anyone can call it, e.g. a keeper
+    /// bot, the quota is taken from the schedule owner. Missed periods are executed one call at a
+    /// time.
+    ///
+    /// Requirements:
+    /// - The next period must be due and not after `end_at`.
+    /// - The schedule balance must cover the list total.
+    /// - Enough gas must be attached for the transfers, see `execute_due_gas`.
+    pub fn execute_due(&mut self, schedule_id: u64) -> Promise {
+        let mut schedule = self
+            .schedules
+            .get(&schedule_id)
+            .expect("Schedule not found");
+        assert!(!schedule.cancelled, "Schedule is cancelled");
+        let feature = match schedule.token_id {
+            Some(_) => Feature::DistributeFt,
+            None => Feature::DistributeNear,
+        };
+        self.assert_not_paused(feature);
+        let period = schedule.next_period;
+        let due_at = schedule.due_at(period);
+        assert!(due_at <= schedule.end_at.0, "Schedule is finished");
+        assert!(env::block_timestamp() >= due_at, "No period is due");
+
+        let (receivers, amount) = self.finalized_list_entries(schedule.list_id);
+        let required_gas = execute_due_gas(schedule.token_id.is_some(), receivers.len() as u64);
+        assert!(
+            env::prepaid_gas() - env::used_gas() >= required_gas,
+            "Not enough gas attached, {} receivers need {} gas",
+            receivers.len(),
+            required_gas.0
+        );
+        let total_amount: Balance = amount.iter().map(|x| x.0).sum();
+        assert!(
+            schedule.balance.0 >= total_amount,
+            "Not enough schedule balance"
+        );
+        schedule.balance = (schedule.balance.0 - total_amount).into();
+        schedule.next_period += 1;
+        self.schedules.insert(&schedule_id, &schedule);
+        if schedule.token_id.is_none() {
+            self.release_held_near(total_amount);
+        }
+        let charge = self.charge_quota(None, schedule.owner_id, receivers.len() as u128);
+
+        let transfer_promise = match schedule.token_id {
+            Some(token_id) => ft_transfers(&token_id, &receivers, &amount),
+            None => near_transfers(&receivers, &amount),
+        };
+        let callback_gas = execute_schedule_callback_gas(receivers.len() as u64);
+        transfer_promise.then(ext_self::callback_execute_schedule(
+            schedule_id,
+            period,
+            amount,
+            charge,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            callback_gas,
+        ))
+    }
+
+    #[private]
+    pub fn callback_execute_schedule(
+        &mut self,
+        schedule_id: u64,
+        period: u64,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+    ) {
+        let (refund, total_failed_transfer) = failed_transfers(&amount);
+        self.refund_quota(&charge, total_failed_transfer);
+        let mut schedule = self
+            .schedules
+            .get(&schedule_id)
+            .expect("Schedule not found");
+        if schedule.cancelled {
+            self.refund_funds(schedule.token_id.clone(), &schedule.owner_id, refund);
+        } else {
+            schedule.balance = (schedule.balance.0 + refund).into();
+            if schedule.token_id.is_none() {
+                self.hold_near(refund);
+            }
+        }
+
+        self.schedule_periods.insert(
+            &(schedule_id, period),
+            &PeriodResult {
+                executed_at: env::block_timestamp().into(),
+                succeeded: (amount.len() as u128 - total_failed_transfer) as u64,
+                failed: total_failed_transfer as u64,
+                failed_amount: refund.into(),
+            },
+        );
+        self.schedules.insert(&schedule_id, &schedule);
+    }
+
+    /// Stop `schedule_id`, refund its balance to the owner and release the storage reserved for
+    /// the periods left. Period records are kept. Tokens that cannot be refunded stay claimable
+    /// with `claim_token_refund`.
+    ///
+    /// Requirements:
+    /// - The caller must be the schedule owner.
+    pub fn cancel_schedule(&mut self, schedule_id: u64) {
+        let mut schedule = self
+            .schedules
+            .get(&schedule_id)
+            .expect("Schedule not found");
+        assert_eq!(
+            env::predecessor_account_id(),
+            schedule.owner_id,
+            "Only the schedule owner can cancel it"
+        );
+        assert!(!schedule.cancelled, "Schedule is cancelled");
+        let balance = schedule.balance.0;
+        schedule.cancelled = true;
+        schedule.balance = 0.into();
+        self.schedules.insert(&schedule_id, &schedule);
+        let periods_left = schedule.num_periods().saturating_sub(schedule.next_period);
+        self.release_storage(&schedule.owner_id, period_result_storage(periods_left));
+        let scheduled = self.scheduled_lists.get(&schedule.list_id).unwrap_or(1);
+        if scheduled > 1 {
+            self.scheduled_lists
+                .insert(&schedule.list_id, &(scheduled - 1));
+        } else {
+            self.scheduled_lists.remove(&schedule.list_id);
+        }
+
+        if schedule.token_id.is_none() {
+            self.release_held_near(balance);
+        }
+        self.refund_funds(schedule.token_id.clone(), &schedule.owner_id, balance);
+    }
+
+    /// Return `schedule_id`.
+    pub fn get_schedule(&self, schedule_id: u64) -> Option<Schedule> {
+        self.schedules.get(&schedule_id)
+    }
+
+    /// Return the result of `period` of `schedule_id` once it is executed.
+    pub fn get_period_result(&self, schedule_id: u64, period: u64) -> Option<PeriodResult> {
+        self.schedule_periods.get(&(schedule_id, period))
+    }
+
+    /// Add `amount` of tokens sent by `ft_on_transfer` to the balance of `schedule_id`.
+    pub(crate) fn fund_token_schedule(&mut self, schedule_id: u64, amount: Balance) {
+        let mut schedule = self
+            .schedules
+            .get(&schedule_id)
+            .expect("Schedule not found");
+        assert!(!schedule.cancelled, "Schedule is cancelled");
+        assert_eq!(
+            schedule.token_id,
+            Some(env::predecessor_account_id()),
+            "Schedule distributes another token"
+        );
+        schedule.balance = (schedule.balance.0 + amount).into();
+        self.schedules.insert(&schedule_id, &schedule);
+    }
+}
+
+/// Storage of the result records of `periods` periods.
+fn period_result_storage(periods: u64) -> StorageUsage {
+    periods
+        .checked_mul(PERIOD_RESULT_STORAGE)
+        .expect("Too many schedule periods")
+}
+
+/// Gas of `callback_execute_schedule` for a list of `receivers` receivers.
+fn execute_schedule_callback_gas(receivers: u64) -> Gas {
+    GAS_FOR_EXECUTE_SCHEDULE_CALLBACK + GAS_PER_SCHEDULE_RECEIVER * receivers
+}
+
+/// Gas `execute_due` needs after its own execution to pay `receivers` receivers of a token
+/// schedule, or of a NEAR schedule if `token` is false.
+pub(crate) fn execute_due_gas(token: bool, receivers: u64) -> Gas {
+    let transfer_gas = if token {
+        GAS_FOR_FT_TRANSFER * receivers
+    } else {
+        Gas(0)
+    };
+    transfer_gas + execute_schedule_callback_gas(receivers)
+}
@@ -0,0 +1,292 @@
+use crate::*;
+
+/// Bytes a payment can add in its callback: the quota balance, a quota lot or subscription and
+/// the referral of the payer. Callbacks cannot fail, so this much is checked up front.
+const PAYMENT_STORAGE_RESERVE: StorageUsage = 300;
+
+/// NEP-145 storage balance of an account. `used` is the storage in bytes the account pays for,
+/// including its own registration.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StorageAccount {
+    pub deposit: Balance,
+    pub used: StorageUsage,
+}
+
+impl StorageAccount {
+    pub fn available(&self) -> Balance {
+        self.deposit
+            .saturating_sub(self.used as Balance * env::storage_byte_cost())
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// A payable method to deposit NEAR for the storage of `account_id`, or of
+    /// `env::predecessor_account_id`. Storage used by quota, allowances, subscriptions, lists,
+    /// schedules, vestings and escrows is paid from this balance. With `registration_only` only
+    /// the minimum balance is kept when registering and the rest of the deposit is refunded.
+    #[payable]
+    pub fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        self.assert_not_paused(Feature::StorageDeposit);
+        let amount = env::attached_deposit();
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let registration_only = registration_only.unwrap_or(false);
+        let mut account = match self.storage_accounts.get(&account_id) {
+            Some(account) => {
+                if registration_only {
+                    log!("The account is already registered, refunding the deposit");
+                    if amount > 0 {
+                        Promise::new(env::predecessor_account_id()).transfer(amount);
+                    }
+                    return self.storage_balance_of(account_id).unwrap();
+                }
+                account
+            }
+            None => {
+                let min_balance = self.storage_balance_bounds().min.0;
+                assert!(
+                    amount >= min_balance,
+                    "The attached deposit is less than the minimum storage balance"
+                );
+                StorageAccount {
+                    deposit: 0,
+                    used: self.account_storage_usage,
+                }
+            }
+        };
+        let mut deposit = amount;
+        if registration_only {
+            let min_balance = self.storage_balance_bounds().min.0;
+            if amount > min_balance {
+                Promise::new(env::predecessor_account_id()).transfer(amount - min_balance);
+            }
+            deposit = min_balance;
+        }
+        account.deposit += deposit;
+        self.storage_accounts.insert(&account_id, &account);
+        self.hold_near(deposit);
+        self.storage_balance_of(account_id).unwrap()
+    }
+
+    /// Withdraw `amount`, or all, of the available storage balance of
+    /// `env::predecessor_account_id`.
+    ///
+    /// Requirements:
+    /// - Exactly 1 yoctoNEAR must be attached.
+    /// - `amount` must not exceed the available balance.
+    #[payable]
+    pub fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_eq!(
+            env::attached_deposit(),
+            1,
+            "Requires attached deposit of exactly 1 yoctoNEAR"
+        );
+        let account_id = env::predecessor_account_id();
+        let mut account = self
+            .storage_accounts
+            .get(&account_id)
+            .expect("The account is not registered");
+        let available = account.available();
+        let amount = amount.map(|amount| amount.0).unwrap_or(available);
+        assert!(
+            amount <= available,
+            "The amount is greater than the available storage balance"
+        );
+        if amount > 0 {
+            account.deposit -= amount;
+            self.storage_accounts.insert(&account_id, &account);
+            self.release_held_near(amount);
+            Promise::new(account_id.clone()).transfer(amount);
+        }
+        self.storage_balance_of(account_id).unwrap()
+    }
+
+    /// Remove the registration of `env::predecessor_account_id` and refund its storage balance.
+    /// Return `false` if the account is not registered. State paid by the account must be
+    /// removed first, `force` is not supported.
+    ///
+    /// Requirements:
+    /// - Exactly 1 yoctoNEAR must be attached.
+    #[payable]
+    pub fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_eq!(
+            env::attached_deposit(),
+            1,
+            "Requires attached deposit of exactly 1 yoctoNEAR"
+        );
+        assert!(!force.unwrap_or(false), "force is not supported");
+        let account_id = env::predecessor_account_id();
+        let account = match self.storage_accounts.get(&account_id) {
+            Some(account) => account,
+            None => return false,
+        };
+        assert!(
+            account.used <= self.account_storage_usage,
+            "Can't unregister the account with the positive storage usage"
+        );
+        self.storage_accounts.remove(&account_id);
+        self.release_held_near(account.deposit);
+        Promise::new(account_id).transfer(account.deposit + 1);
+        true
+    }
+
+    /// Return the minimum storage balance, the cost of the registration. There is no maximum.
+    pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: (self.account_storage_usage as Balance * env::storage_byte_cost()).into(),
+            max: None,
+        }
+    }
+
+    /// Return the storage balance of `account_id`, `None` if it is not registered.
+    pub fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_accounts
+            .get(&account_id)
+            .map(|account| StorageBalance {
+                total: account.deposit.into(),
+                available: account.available().into(),
+            })
+    }
+
+    /// Charge `account_id` for the storage used since `initial_storage_usage`, or release the
+    /// storage freed since then.
+    ///
+    /// Requirements:
+    /// - The account must be registered and its storage balance must cover the storage used.
+    pub(crate) fn charge_storage(
+        &mut self,
+        account_id: &AccountId,
+        initial_storage_usage: StorageUsage,
+    ) {
+        let grew = env::storage_usage() > initial_storage_usage;
+        let account = self.record_storage(account_id, initial_storage_usage);
+        if grew {
+            let account = account.expect("The account is not registered, call storage_deposit");
+            assert!(
+                account.deposit >= account.used as Balance * env::storage_byte_cost(),
+                "Not enough storage balance, call storage_deposit"
+            );
+        }
+    }
+
+    /// Make sure `account_id` can pay for the storage of a payment whose state is written in a
+    /// callback. An account that is not registered, or whose storage balance is too low, is
+    /// registered or topped up with part of the payment `amount`. Return the rest of `amount`.
+    pub(crate) fn take_payment_storage(
+        &mut self,
+        account_id: &AccountId,
+        amount: Balance,
+    ) -> Balance {
+        let mut account = self
+            .storage_accounts
+            .get(account_id)
+            .unwrap_or(StorageAccount {
+                deposit: 0,
+                used: self.account_storage_usage,
+            });
+        let required =
+            (account.used + PAYMENT_STORAGE_RESERVE) as Balance * env::storage_byte_cost();
+        let missing = required.saturating_sub(account.deposit);
+        if missing == 0 {
+            return amount;
+        }
+        assert!(
+            amount >= missing,
+            "The attached deposit must cover {} yoctoNEAR of storage, or call storage_deposit",
+            missing
+        );
+        account.deposit += missing;
+        self.storage_accounts.insert(account_id, &account);
+        self.hold_near(missing);
+        amount - missing
+    }
+
+    /// Add the attached deposit to the storage balance of `account_id`, registering it if needed,
+    /// so payable methods that grow the state can be called with their storage cost attached.
+    pub(crate) fn deposit_attached_storage(&mut self, account_id: &AccountId) {
+        let amount = env::attached_deposit();
+        if amount == 0 {
+            return;
+        }
+        let mut account = self
+            .storage_accounts
+            .get(account_id)
+            .unwrap_or(StorageAccount {
+                deposit: 0,
+                used: self.account_storage_usage,
+            });
+        account.deposit += amount;
+        self.storage_accounts.insert(account_id, &account);
+        self.hold_near(amount);
+    }
+
+    /// Charge `account_id` for `bytes` of storage written later in callbacks, which cannot fail.
+    ///
+    /// Requirements:
+    /// - The account must be registered and its storage balance must cover the storage used.
+    pub(crate) fn reserve_storage(&mut self, account_id: &AccountId, bytes: StorageUsage) {
+        let mut account = self
+            .storage_accounts
+            .get(account_id)
+            .expect("The account is not registered, call storage_deposit");
+        account.used += bytes;
+        assert!(
+            account.deposit >= account.used as Balance * env::storage_byte_cost(),
+            "Not enough storage balance, call storage_deposit"
+        );
+        self.storage_accounts.insert(account_id, &account);
+    }
+
+    /// Release `bytes` reserved with `reserve_storage` that will not be written.
+    pub(crate) fn release_storage(&mut self, account_id: &AccountId, bytes: StorageUsage) {
+        if let Some(mut account) = self.storage_accounts.get(account_id) {
+            account.used = account
+                .used
+                .saturating_sub(bytes)
+                .max(self.account_storage_usage);
+            self.storage_accounts.insert(account_id, &account);
+        }
+    }
+
+    /// Record the storage used or freed by `account_id` since `initial_storage_usage` without
+    /// checking its balance, so callbacks never fail on it. Storage of unregistered accounts is
+    /// paid by the contract. Return the updated account.
+    pub(crate) fn record_storage(
+        &mut self,
+        account_id: &AccountId,
+        initial_storage_usage: StorageUsage,
+    ) -> Option<StorageAccount> {
+        let storage_usage = env::storage_usage();
+        let mut account = self.storage_accounts.get(account_id)?;
+        if storage_usage > initial_storage_usage {
+            account.used += storage_usage - initial_storage_usage;
+        } else {
+            let freed = initial_storage_usage - storage_usage;
+            account.used = account
+                .used
+                .saturating_sub(freed)
+                .max(self.account_storage_usage);
+        }
+        self.storage_accounts.insert(account_id, &account);
+        Some(account)
+    }
+
+    /// Measure the storage of the registration of a longest account id.
+    pub(crate) fn measure_account_storage_usage(&mut self) {
+        let initial_storage_usage = env::storage_usage();
+        let tmp_account_id = AccountId::new_unchecked("a".repeat(64));
+        self.storage_accounts.insert(
+            &tmp_account_id,
+            &StorageAccount {
+                deposit: 0,
+                used: 0,
+            },
+        );
+        self.account_storage_usage = env::storage_usage() - initial_storage_usage;
+        self.storage_accounts.remove(&tmp_account_id);
+    }
+}
@@ -0,0 +1,235 @@
+use crate::*;
+
+/// Number of decimals of `SubscriptionPlan::price_usd`, i.e. prices are in USD cents.
+pub const PLAN_PRICE_USD_DECIMALS: u32 = 2;
+const GAS_FOR_SUBSCRIBE_CALLBACK: Gas = Gas(10_000_000_000_000);
+
+/// A time-based plan defined by the owner.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SubscriptionPlan {
+    /// Price of one period in USD cents, converted to NEAR with the oracle price.
+    pub price_usd: U128,
+    /// Length of one period in nanoseconds.
+    pub duration: U64,
+    /// Maximum number of addresses per period, `None` for unlimited.
+    pub address_cap: Option<u128>,
+    /// Whether new subscriptions to the plan are accepted.
+    pub active: bool,
+}
+
+/// The subscription of an account.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Subscription {
+    pub plan_id: u64,
+    /// Block timestamp in nanoseconds at which the subscription ends.
+    pub expires_at: U64,
+    /// Addresses covered until `expires_at`, `None` for unlimited.
+    pub address_cap: Option<u128>,
+    pub addresses_used: u128,
+}
+
+impl Subscription {
+    pub fn is_active(&self) -> bool {
+        env::block_timestamp() < self.expires_at.0
+    }
+
+    /// Number of addresses that can still be sent to, `u128::MAX` when unlimited.
+    pub fn remaining(&self) -> u128 {
+        match self.address_cap {
+            Some(cap) => cap.saturating_sub(self.addresses_used),
+            None => u128::MAX,
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Define a new subscription plan and return its id.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner.
+    ///
+    /// Arguments:
+    /// - `price_usd`: the price of one period in USD cents.
+    /// - `duration`: the length of one period in nanoseconds.
+    /// - `address_cap`: the maximum number of addresses per period, `None` for unlimited.
+    pub fn add_subscription_plan(
+        &mut self,
+        price_usd: U128,
+        duration: U64,
+        address_cap: Option<u128>,
+    ) -> u64 {
+        self.assert_owner();
+        assert!(price_usd.0 > 0, "price must be positive");
+        assert!(duration.0 > 0, "duration must be positive");
+        let plan_id = self.next_plan_id;
+        self.next_plan_id += 1;
+        self.plans.insert(
+            &plan_id,
+            &SubscriptionPlan {
+                price_usd,
+                duration,
+                address_cap,
+                active: true,
+            },
+        );
+        plan_id
+    }
+
+    /// Stop accepting new subscriptions and renewals for `plan_id`. Running subscriptions are kept.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner.
+    pub fn deactivate_subscription_plan(&mut self, plan_id: u64) {
+        self.assert_owner();
+        let mut plan = self.plans.get(&plan_id).expect("Plan not found");
+        plan.active = false;
+        self.plans.insert(&plan_id, &plan);
+    }
+
+    /// A payable method to subscribe to `plan_id` for one period. The attached deposit must cover
+    /// the plan price at the current oracle price, the excess is refunded. Subscribing again to
+    /// the active plan extends it by one period and adds the plan's address cap. The price is
+    /// reduced by the fee discount of the caller, fee-exempt accounts cannot subscribe. A caller
+    /// that is not registered with `storage_deposit` or lacks storage balance pays it from the
+    /// attached deposit first. Plans are priced in USD, so subscribing needs oracle mode.
+    ///
+    /// Arguments:
+    /// - `plan_id`: the id of the plan.
+    /// - `referrer_id`: the optional partner account credited with the referral share of the price.
+    #[payable]
+    pub fn subscribe(&mut self, plan_id: u64, referrer_id: Option<AccountId>) -> Promise {
+        self.assert_not_paused(Feature::PayFee);
+        assert!(
+            !matches!(self.oracle_mode, OracleMode::FixedPrice { .. }),
+            "Plans are priced in USD and cannot be subscribed to in fixed price mode"
+        );
+        let plan = self.plans.get(&plan_id).expect("Plan not found");
+        assert!(plan.active, "Plan is not active");
+        let account_id = env::predecessor_account_id();
+        assert!(
+            !self.is_fee_exempt(&account_id),
+            "Account is exempt from fees"
+        );
+        if let Some(subscription) = self.subscriptions.get(&account_id) {
+            assert!(
+                !subscription.is_active() || subscription.plan_id == plan_id,
+                "Another subscription is active"
+            );
+        }
+        let amount = self.take_payment_storage(&account_id, env::attached_deposit());
+        self.query_near_price().then(ext_self::callback_subscribe(
+            plan_id,
+            account_id,
+            amount.into(),
+            referrer_id,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            self.price_callback_gas(GAS_FOR_SUBSCRIBE_CALLBACK),
+        ))
+    }
+
+    #[private]
+    pub fn callback_subscribe(
+        &mut self,
+        plan_id: u64,
+        account_id: AccountId,
+        amount: U128,
+        referrer_id: Option<AccountId>,
+    ) {
+        let plan = self.plans.get(&plan_id).expect("Plan not found");
+        let price = match self.near_price_result() {
+            Some(entry) => self.discounted_fee(
+                &account_id,
+                entry.usd_to_yocto(plan.price_usd.0, PLAN_PRICE_USD_DECIMALS),
+            ),
+            None => return self.refund_near(&account_id, amount.0),
+        };
+        let current = self.subscriptions.get(&account_id);
+        let renewal = current
+            .as_ref()
+            .map(|subscription| subscription.is_active() && subscription.plan_id == plan_id)
+            .unwrap_or(false);
+        let conflict = current
+            .as_ref()
+            .map(|subscription| subscription.is_active() && subscription.plan_id != plan_id)
+            .unwrap_or(false);
+        if amount.0 < price || !plan.active || conflict {
+            return self.refund_near(&account_id, amount.0);
+        }
+
+        let redundant_coin = amount.0 - price;
+        if redundant_coin != 0 {
+            Promise::new(account_id.clone()).transfer(redundant_coin);
+        }
+        let subscription = match current {
+            Some(mut subscription) if renewal => {
+                subscription.expires_at = (subscription.expires_at.0 + plan.duration.0).into();
+                subscription.address_cap = match (subscription.address_cap, plan.address_cap) {
+                    (Some(cap), Some(plan_cap)) => Some(cap + plan_cap),
+                    _ => None,
+                };
+                subscription
+            }
+            _ => Subscription {
+                plan_id,
+                expires_at: (env::block_timestamp() + plan.duration.0).into(),
+                address_cap: plan.address_cap,
+                addresses_used: 0,
+            },
+        };
+        let initial_storage_usage = env::storage_usage();
+        self.subscriptions.insert(&account_id, &subscription);
+        let referral_share = self.credit_referral(referrer_id, &account_id, price);
+        self.collected_fees += price - referral_share;
+        self.record_storage(&account_id, initial_storage_usage);
+
+        let subscribe_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::Subscribe(SubscribeLog {
+                amount: price.to_string(),
+                refund: redundant_coin.to_string(),
+                user_id: account_id.to_string(),
+                plan_id,
+                expires_at: subscription.expires_at.0.to_string(),
+            }),
+        };
+        env::log_str(&subscribe_log.to_string());
+    }
+
+    /// Return all subscription plans with their ids.
+    pub fn subscription_plans(&self) -> Vec<(u64, SubscriptionPlan)> {
+        self.plans.to_vec()
+    }
+
+    /// Return the subscription of `account_id`, active or expired.
+    pub fn subscription_of(&self, account_id: AccountId) -> Option<Subscription> {
+        self.subscriptions.get(&account_id)
+    }
+
+    /// Use up to `amount` addresses of the active subscription of `account_id` and return
+    /// the number of addresses it covered.
+    pub(crate) fn use_subscription(&mut self, account_id: &AccountId, amount: u128) -> u128 {
+        let mut subscription = match self.subscriptions.get(account_id) {
+            Some(subscription) if subscription.is_active() => subscription,
+            _ => return 0,
+        };
+        let covered = amount.min(subscription.remaining());
+        if covered > 0 {
+            subscription.addresses_used += covered;
+            self.subscriptions.insert(account_id, &subscription);
+        }
+        covered
+    }
+
+    /// Give back `amount` addresses to the subscription of `account_id`.
+    pub(crate) fn release_subscription(&mut self, account_id: &AccountId, amount: u128) {
+        if let Some(mut subscription) = self.subscriptions.get(account_id) {
+            subscription.addresses_used = subscription.addresses_used.saturating_sub(amount);
+            self.subscriptions.insert(account_id, &subscription);
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use crate::*;
+
+const GAS_FOR_FT_TRANSFER_CALL: Gas = Gas(35_000_000_000_000);
+const GAS_FOR_DEX_SWAP: Gas = Gas(20_000_000_000_000);
+const GAS_FOR_DEX_WITHDRAW: Gas = Gas(25_000_000_000_000);
+const GAS_FOR_SWAP_DEPOSIT_CALLBACK: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_SWAP_CALLBACK: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_SWAP_WITHDRAW_CALLBACK: Gas = Gas(30_000_000_000_000);
+const GAS_FOR_SWAP_REFUND_CALLBACK: Gas = Gas(15_000_000_000_000);
+/// Gas used by the swap callbacks for each receiver, besides its `ft_transfer`.
+const GAS_PER_SWAP_RECEIVER: Gas = Gas(1_000_000_000_000);
+/// Maximum number of receivers of a swap distribution, so all transfers fit in one callback.
+pub const MAX_SWAP_RECEIVERS: usize = 30;
+
+/// Swap step of a token distribution, given in the `ft_transfer_call` message. The received
+/// tokens are swapped in `pool_id` of the configured DEX and the `amount` of the message is
+/// sent in `token_out`. Output above the distributed total is returned to the sender.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapStep {
+    pub pool_id: u64,
+    pub token_out: AccountId,
+    /// The swap fails if it returns less, must cover the distributed total.
+    pub min_amount_out: U128,
+}
+
+/// A swap action of a Ref Finance compatible DEX.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapAction {
+    pub pool_id: u64,
+    pub token_in: AccountId,
+    pub amount_in: Option<U128>,
+    pub token_out: AccountId,
+    pub min_amount_out: U128,
+}
+
+/// A token distribution waiting for its swap.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PendingSwap {
+    pub dex_id: AccountId,
+    pub token_in: AccountId,
+    pub amount_in: U128,
+    pub step: SwapStep,
+    pub receivers: Vec<AccountId>,
+    pub amount: Vec<U128>,
+    pub charge: QuotaCharge,
+}
+
+#[ext_contract(ext_dex)]
+pub trait RefExchange {
+    fn swap(&mut self, actions: Vec<SwapAction>, referral_id: Option<AccountId>) -> U128;
+    fn withdraw(&mut self, token_id: AccountId, amount: U128, unregister: Option<bool>);
+}
+
+#[ext_contract(ext_ft_call)]
+pub trait FungibleTokenCall {
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> U128;
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Set the Ref Finance compatible DEX used by swap distributions, `None` disables them.
+    /// The contract must be registered on the DEX and on the output tokens.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner.
+    pub fn set_dex(&mut self, dex_id: Option<AccountId>) {
+        self.assert_owner();
+        self.dex_id = dex_id;
+    }
+
+    /// Return the DEX used by swap distributions.
+    pub fn dex(&self) -> Option<AccountId> {
+        self.dex_id.clone()
+    }
+
+    /// Withdraw the tokens of failed swap distributions of `env::predecessor_account_id` left on
+    /// `dex_id` and send them back. The tokens stay withdrawable if the withdrawal fails.
+    pub fn withdraw_dex_refund(&mut self, dex_id: AccountId, token_id: AccountId) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let initial_storage_usage = env::storage_usage();
+        let amount = self
+            .dex_refunds
+            .remove(&(account_id.clone(), dex_id.clone(), token_id.clone()))
+            .expect("No tokens to withdraw");
+        self.record_storage(&account_id, initial_storage_usage);
+        ext_dex::withdraw(
+            token_id.clone(),
+            amount.into(),
+            None,
+            dex_id.clone(),
+            1, // yocto NEAR to attach
+            GAS_FOR_DEX_WITHDRAW,
+        )
+        .then(ext_self::callback_swap_refund(
+            dex_id,
+            token_id,
+            account_id,
+            amount.into(),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_SWAP_REFUND_CALLBACK,
+        ))
+    }
+
+    /// Return the tokens of failed swap distributions of `account_id` left on `dex_id`.
+    pub fn dex_refund(
+        &self,
+        account_id: AccountId,
+        dex_id: AccountId,
+        token_id: AccountId,
+    ) -> U128 {
+        self.dex_refunds
+            .get(&(account_id, dex_id, token_id))
+            .unwrap_or(0)
+            .into()
+    }
+
+    /// Swap the deposited tokens once they reached the DEX. Return the amount of `token_in`
+    /// to give back to the sender.
+    #[private]
+    pub fn callback_swap_deposit(&mut self, swap: PendingSwap) -> U128 {
+        let receivers = swap.receivers.len() as u64;
+        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
+        let used: Balance = match env::promise_result(0) {
+            PromiseResult::Successful(result) => serde_json::from_slice::<U128>(&result)
+                .map(|used| used.0)
+                .unwrap_or(0),
+            _ => 0,
+        };
+        if used < swap.amount_in.0 {
+            log!(
+                "deposit to the DEX failed, refunding {}",
+                swap.amount_in.0 - used
+            );
+            self.refund_quota(&swap.charge, swap.charge.amount);
+            if used > 0 {
+                self.add_dex_refund(&swap.charge.spender_id, swap.dex_id, swap.token_in, used);
+            }
+            return (swap.amount_in.0 - used).into();
+        }
+        ext_dex::swap(
+            vec![SwapAction {
+                pool_id: swap.step.pool_id,
+                token_in: swap.token_in.clone(),
+                amount_in: Some(swap.amount_in),
+                token_out: swap.step.token_out.clone(),
+                min_amount_out: swap.step.min_amount_out,
+            }],
+            None,
+            swap.dex_id.clone(),
+            1, // yocto NEAR to attach
+            GAS_FOR_DEX_SWAP,
+        )
+        .then(ext_self::callback_swap(
+            swap,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            swap_callback_gas(receivers),
+        ));
+        0.into()
+    }
+
+    #[private]
+    pub fn callback_swap(&mut self, swap: PendingSwap) {
+        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
+        let amount_out = match env::promise_result(0) {
+            PromiseResult::Successful(result) => serde_json::from_slice::<U128>(&result).ok(),
+            _ => None,
+        };
+        let dex_id = swap.dex_id.clone();
+        let receivers = swap.receivers.len() as u64;
+        match amount_out {
+            Some(amount_out) => {
+                ext_dex::withdraw(
+                    swap.step.token_out.clone(),
+                    amount_out,
+                    None,
+                    dex_id,
+                    1, // yocto NEAR to attach
+                    GAS_FOR_DEX_WITHDRAW,
+                )
+                .then(ext_self::callback_swap_withdraw(
+                    swap,
+                    amount_out,
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    swap_withdraw_callback_gas(receivers),
+                ));
+            }
+            None => {
+                log!("swap failed, refunding {}", swap.amount_in.0);
+                self.refund_quota(&swap.charge, swap.charge.amount);
+                ext_dex::withdraw(
+                    swap.token_in.clone(),
+                    swap.amount_in,
+                    None,
+                    dex_id,
+                    1, // yocto NEAR to attach
+                    GAS_FOR_DEX_WITHDRAW,
+                )
+                .then(ext_self::callback_swap_refund(
+                    swap.dex_id,
+                    swap.token_in,
+                    swap.charge.spender_id,
+                    swap.amount_in,
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    GAS_FOR_SWAP_REFUND_CALLBACK,
+                ));
+            }
+        }
+    }
+
+    /// Distribute the swapped tokens once they are withdrawn from the DEX.
+    #[private]
+    pub fn callback_swap_withdraw(&mut self, swap: PendingSwap, amount_out: U128) {
+        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
+        if !matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+            log!(
+                "withdrawing {} {} from the DEX failed",
+                amount_out.0,
+                swap.step.token_out
+            );
+            self.refund_quota(&swap.charge, swap.charge.amount);
+            self.add_dex_refund(
+                &swap.charge.spender_id,
+                swap.dex_id,
+                swap.step.token_out,
+                amount_out.0,
+            );
+            return;
+        }
+        let total_amount: Balance = swap.amount.iter().map(|x| x.0).sum();
+        let token_out = swap.step.token_out;
+        self.refund_funds(
+            Some(token_out.clone()),
+            &swap.charge.spender_id,
+            amount_out.0.saturating_sub(total_amount),
+        );
+        ft_transfers(&token_out, &swap.receivers, &swap.amount).then(
+            ext_self::callback_transfer_funds(
+                Some(token_out),
+                swap.amount,
+                swap.charge,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_TRANSFER_FUNDS_CALLBACK,
+            ),
+        );
+    }
+
+    /// Give the tokens of a failed swap back to the sender once they are withdrawn from the DEX,
+    /// or keep them withdrawable with `withdraw_dex_refund`.
+    #[private]
+    pub fn callback_swap_refund(
+        &mut self,
+        dex_id: AccountId,
+        token_id: AccountId,
+        sender_id: AccountId,
+        amount: U128,
+    ) {
+        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => self.refund_funds(Some(token_id), &sender_id, amount.0),
+            _ => self.add_dex_refund(&sender_id, dex_id, token_id, amount.0),
+        }
+    }
+
+    /// Keep `amount` of `token_id` left on `dex_id` withdrawable by `account_id`.
+    fn add_dex_refund(
+        &mut self,
+        account_id: &AccountId,
+        dex_id: AccountId,
+        token_id: AccountId,
+        amount: Balance,
+    ) {
+        log!(
+            "{} {} left on {}, withdraw it with withdraw_dex_refund",
+            amount,
+            token_id,
+            dex_id
+        );
+        let initial_storage_usage = env::storage_usage();
+        let key = (account_id.clone(), dex_id, token_id);
+        let balance = self.dex_refunds.get(&key).unwrap_or(0);
+        self.dex_refunds.insert(&key, &(balance + amount));
+        self.record_storage(account_id, initial_storage_usage);
+    }
+
+    /// Send the tokens received by `ft_on_transfer` to the DEX, swap them and distribute the
+    /// output. Return the amount of received tokens to give back to the sender.
+    pub(crate) fn swap_and_distribute(&mut self, swap: PendingSwap) -> PromiseOrValue<U128> {
+        let total_amount: Balance = swap.amount.iter().map(|x| x.0).sum();
+        assert!(
+            swap.step.min_amount_out.0 >= total_amount,
+            "min_amount_out must cover the distributed amount"
+        );
+        let receivers = swap.receivers.len();
+        assert!(
+            receivers <= MAX_SWAP_RECEIVERS,
+            "swap distributions are limited to {} receivers",
+            MAX_SWAP_RECEIVERS
+        );
+        let required_gas = distribute_swap_gas(receivers as u64);
+        assert!(
+            env::prepaid_gas() - env::used_gas() >= required_gas,
+            "Not enough gas attached, {} receivers need {} gas",
+            receivers,
+            required_gas.0
+        );
+        ext_ft_call::ft_transfer_call(
+            swap.dex_id.clone(),
+            swap.amount_in,
+            None,
+            String::new(),
+            swap.token_in.clone(),
+            1, // yocto NEAR to attach
+            GAS_FOR_FT_TRANSFER_CALL,
+        )
+        .then(ext_self::callback_swap_deposit(
+            swap,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            swap_deposit_callback_gas(receivers as u64),
+        ))
+        .into()
+    }
+}
+
+/// Gas of `callback_swap_withdraw`, including the transfers it makes.
+fn swap_withdraw_callback_gas(receivers: u64) -> Gas {
+    GAS_FOR_SWAP_WITHDRAW_CALLBACK + (GAS_PER_SWAP_RECEIVER + GAS_FOR_FT_TRANSFER) * receivers
+}
+
+/// Gas of `callback_swap`, including the withdrawal from the DEX and the distribution.
+fn swap_callback_gas(receivers: u64) -> Gas {
+    GAS_FOR_SWAP_CALLBACK + GAS_FOR_DEX_WITHDRAW + swap_withdraw_callback_gas(receivers)
+}
+
+/// Gas of `callback_swap_deposit`, including the swap and everything that follows it.
+fn swap_deposit_callback_gas(receivers: u64) -> Gas {
+    GAS_FOR_SWAP_DEPOSIT_CALLBACK + GAS_FOR_DEX_SWAP + swap_callback_gas(receivers)
+}
+
+/// Gas a swap distribution to `receivers` receivers needs after `ft_on_transfer`.
+pub(crate) fn distribute_swap_gas(receivers: u64) -> Gas {
+    GAS_FOR_FT_TRANSFER_CALL + swap_deposit_callback_gas(receivers)
+}
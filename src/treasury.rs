@@ -0,0 +1,105 @@
+use crate::*;
+
+const GAS_FOR_WITHDRAW_FEES_CALLBACK: Gas = Gas(5_000_000_000_000);
+
+/// Service fee revenue of the contract.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeRevenue {
+    /// Fees collected and not withdrawn yet.
+    pub collected_fees: U128,
+    /// Total fees withdrawn to the treasury so far.
+    pub withdrawn_fees: U128,
+    pub treasury_id: Option<AccountId>,
+    /// NEAR held for users, which fees are never withdrawn from.
+    pub held_near: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Set the treasury account that is allowed to withdraw collected fees besides the owner.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner.
+    pub fn set_treasury(&mut self, treasury_id: Option<AccountId>) {
+        self.assert_owner();
+        self.treasury_id = treasury_id;
+    }
+
+    /// Withdraw `amount` of collected service fees to `receiver_id`.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner or treasury.
+    /// - `amount` must not exceed the collected fees. NEAR attached to distributions or
+    /// fee payments still in flight is never counted as collected fees.
+    /// - The contract balance left after the withdrawal must cover its storage and the NEAR held
+    /// for users: escrows, schedules, referral rewards and storage balances, which also pay
+    /// for lists. Storage paid from storage balances is counted in both, erring on the safe side.
+    pub fn withdraw_fees(&mut self, amount: U128, receiver_id: AccountId) -> Promise {
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == self.owner_id || self.treasury_id.as_ref() == Some(&caller),
+            "only contract owner or treasury can withdraw fees"
+        );
+        assert!(amount.0 > 0, "amount must be positive");
+        assert!(amount.0 <= self.collected_fees, "Not enough collected fees");
+        let storage_cost = env::storage_usage() as Balance * env::storage_byte_cost();
+        assert!(
+            env::account_balance() >= storage_cost + self.held_near + amount.0,
+            "Not enough balance to cover storage and held NEAR"
+        );
+
+        self.collected_fees -= amount.0;
+        self.withdrawn_fees += amount.0;
+        let withdraw_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::WithdrawFees(WithdrawFeesLog {
+                amount: amount.0.to_string(),
+                receiver_id: receiver_id.to_string(),
+                account_id: caller.to_string(),
+            }),
+        };
+        env::log_str(&withdraw_log.to_string());
+
+        Promise::new(receiver_id.clone())
+            .transfer(amount.0)
+            .then(ext_self::callback_withdraw_fees(
+                amount,
+                receiver_id,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_WITHDRAW_FEES_CALLBACK,
+            ))
+    }
+
+    #[private]
+    pub fn callback_withdraw_fees(&mut self, amount: U128, receiver_id: AccountId) {
+        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
+        if let PromiseResult::Failed = env::promise_result(0) {
+            log!("withdrawal of {} to {} failed", amount.0, receiver_id);
+            self.collected_fees += amount.0;
+            self.withdrawn_fees -= amount.0;
+        }
+    }
+
+    /// Return the service fees collected by the contract.
+    pub fn fee_revenue(&self) -> FeeRevenue {
+        FeeRevenue {
+            collected_fees: self.collected_fees.into(),
+            withdrawn_fees: self.withdrawn_fees.into(),
+            treasury_id: self.treasury_id.clone(),
+            held_near: self.held_near.into(),
+        }
+    }
+
+    /// Hold `amount` of NEAR owed to users so `withdraw_fees` cannot pay it out.
+    pub(crate) fn hold_near(&mut self, amount: Balance) {
+        self.held_near += amount;
+    }
+
+    /// Release NEAR held with `hold_near` once it is paid out or refunded.
+    pub(crate) fn release_held_near(&mut self, amount: Balance) {
+        self.held_near = self.held_near.saturating_sub(amount);
+    }
+}
@@ -0,0 +1,143 @@
+use crate::*;
+use near_sdk::json_types::{Base58CryptoHash, Base64VecU8};
+use near_sdk::CryptoHash;
+use std::convert::TryInto;
+
+/// Time in nanoseconds between staging code and deploying it.
+pub const UPGRADE_TIMELOCK: u64 = 24 * 60 * 60 * 1_000_000_000;
+const GAS_FOR_MIGRATE: Gas = Gas(150_000_000_000_000);
+/// Storage key of the staged wasm, kept out of the contract state so it is never deserialized.
+const STAGED_CODE_KEY: &[u8] = b"STAGED_CODE";
+const STAGED_UPGRADE_KEY: &[u8] = b"STAGED_UPGRADE";
+
+/// Code staged by the owner, deployable from `deployable_at`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StagedUpgrade {
+    pub code_hash: Base58CryptoHash,
+    pub staged_at: U64,
+    pub deployable_at: U64,
+    /// Storage deposit of the staged code, refunded to the owner when it is removed.
+    pub storage_deposit: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// A payable method to store `code` and its hash for an upgrade that can be deployed after
+    /// `UPGRADE_TIMELOCK`. Staging again replaces the code and restarts the timelock. The
+    /// attached deposit must cover the storage used, the excess is refunded.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner.
+    #[payable]
+    pub fn stage_code(&mut self, code: Base64VecU8) -> Base58CryptoHash {
+        self.assert_owner();
+        let code: Vec<u8> = code.into();
+        assert!(!code.is_empty(), "code must not be empty");
+        self.remove_staged_code();
+
+        let initial_storage_usage = env::storage_usage();
+        let code_hash: CryptoHash = env::sha256(&code).try_into().unwrap();
+        let code_hash = Base58CryptoHash::from(code_hash);
+        let now = env::block_timestamp();
+        let mut upgrade = StagedUpgrade {
+            code_hash,
+            staged_at: now.into(),
+            deployable_at: (now + UPGRADE_TIMELOCK).into(),
+            storage_deposit: 0.into(),
+        };
+        env::storage_write(STAGED_CODE_KEY, &code);
+        env::storage_write(STAGED_UPGRADE_KEY, &upgrade.try_to_vec().unwrap());
+        let storage_cost =
+            (env::storage_usage() - initial_storage_usage) as Balance * env::storage_byte_cost();
+        let deposit = env::attached_deposit();
+        assert!(
+            deposit >= storage_cost,
+            "Must attach {} yoctoNEAR to cover storage",
+            storage_cost
+        );
+        upgrade.storage_deposit = storage_cost.into();
+        env::storage_write(STAGED_UPGRADE_KEY, &upgrade.try_to_vec().unwrap());
+        if deposit > storage_cost {
+            Promise::new(env::predecessor_account_id()).transfer(deposit - storage_cost);
+        }
+
+        let stage_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::StageCode(StageCodeLog {
+                code_hash: String::from(&code_hash),
+                deployable_at: upgrade.deployable_at.0.to_string(),
+            }),
+        };
+        env::log_str(&stage_log.to_string());
+        code_hash
+    }
+
+    /// Remove the staged code and refund its storage deposit to the owner.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner.
+    pub fn cancel_staged_code(&mut self) {
+        self.assert_owner();
+        let upgrade = self.remove_staged_code().expect("No code is staged");
+        let cancel_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::CancelStagedCode(CancelStagedCodeLog {
+                code_hash: String::from(&upgrade.code_hash),
+            }),
+        };
+        env::log_str(&cancel_log.to_string());
+    }
+
+    /// Deploy the staged code and call `migrate`, keeping the current oracle mode, in the same batch.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner.
+    /// - `UPGRADE_TIMELOCK` must have passed since the code was staged.
+    pub fn deploy_staged_code(&mut self) -> Promise {
+        self.assert_owner();
+        let upgrade = self.staged_code().expect("No code is staged");
+        assert!(
+            env::block_timestamp() >= upgrade.deployable_at.0,
+            "Staged code is not deployable yet"
+        );
+        let code = env::storage_read(STAGED_CODE_KEY).unwrap();
+        self.remove_staged_code();
+
+        let deploy_log: EventLog = EventLog {
+            standard: EVENT_STANDARD_NAME.to_string(),
+            version: EVENT_VERSION.to_string(),
+            event: EventLogVariant::DeployStagedCode(DeployStagedCodeLog {
+                code_hash: String::from(&upgrade.code_hash),
+            }),
+        };
+        env::log_str(&deploy_log.to_string());
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call(
+                "migrate".to_string(),
+                json!({}).to_string().into_bytes(),
+                NO_DEPOSIT,
+                GAS_FOR_MIGRATE,
+            )
+    }
+
+    /// Return the staged upgrade.
+    pub fn staged_code(&self) -> Option<StagedUpgrade> {
+        env::storage_read(STAGED_UPGRADE_KEY)
+            .map(|upgrade| StagedUpgrade::try_from_slice(&upgrade).expect("failed"))
+    }
+
+    /// Remove the staged code and refund its storage deposit to the owner.
+    fn remove_staged_code(&mut self) -> Option<StagedUpgrade> {
+        let upgrade = self.staged_code()?;
+        env::storage_remove(STAGED_CODE_KEY);
+        env::storage_remove(STAGED_UPGRADE_KEY);
+        if upgrade.storage_deposit.0 > 0 {
+            self.refund_near(&self.owner_id, upgrade.storage_deposit.0);
+        }
+        Some(upgrade)
+    }
+}
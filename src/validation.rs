@@ -0,0 +1,144 @@
+use crate::*;
+use std::collections::HashSet;
+
+const GAS_FOR_VALIDATE_BATCH_CALLBACK: Gas = Gas(5_000_000_000_000);
+
+/// Pre-flight check of one entry of a batch.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ReceiverReport {
+    pub account_id: String,
+    pub amount: U128,
+    pub valid_account_id: bool,
+    /// Whether the receiver is listed earlier in the batch.
+    pub duplicate: bool,
+    /// Whether the receiver is registered on the token, `None` for NEAR batches and invalid ids.
+    pub registered: Option<bool>,
+}
+
+/// Pre-flight check of a batch returned by `validate_batch` and `validate_token_batch`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BatchReport {
+    /// Amount to attach or transfer for the batch.
+    pub total_amount: U128,
+    /// Quota the batch uses, one unit per entry.
+    pub quota_required: u128,
+    /// Quota of the account, `None` if it is exempt from fees.
+    pub quota_available: Option<u128>,
+    /// Whether every check passed.
+    pub valid: bool,
+    pub receivers: Vec<ReceiverReport>,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Check a batch before sending it, without moving any funds. Reports invalid account ids,
+    /// duplicates, the total amount and whether the quota of `quota_owner_id` covers the batch.
+    /// A view method, the registration of receivers on a token is checked by
+    /// `validate_token_batch`.
+    pub fn validate_batch(
+        &self,
+        receivers: Vec<String>,
+        amounts: Vec<U128>,
+        quota_owner_id: AccountId,
+    ) -> BatchReport {
+        let mut report = self.batch_report(receivers, amounts, &quota_owner_id);
+        report.valid = batch_is_valid(&report);
+        report
+    }
+
+    /// Check a token batch like `validate_batch` and query the registration of every receiver
+    /// on `token_id` with `storage_balance_of`, so the report is returned by a callback. The
+    /// quota checked is the one of `quota_owner_id`, or of the caller.
+    pub fn validate_token_batch(
+        &self,
+        token_id: AccountId,
+        receivers: Vec<String>,
+        amounts: Vec<U128>,
+        quota_owner_id: Option<AccountId>,
+    ) -> PromiseOrValue<BatchReport> {
+        let account_id = quota_owner_id.unwrap_or_else(env::predecessor_account_id);
+        let mut report = self.batch_report(receivers, amounts, &account_id);
+        let mut query_promise: Option<Promise> = None;
+        for receiver in report.receivers.iter().filter(|r| r.valid_account_id) {
+            let query = ext_ft::storage_balance_of(
+                receiver.account_id.parse().unwrap(),
+                token_id.clone(),
+                NO_DEPOSIT,
+                GAS_FOR_STORAGE_BALANCE_OF,
+            );
+            query_promise = Some(match query_promise {
+                Some(promise) => promise.and(query),
+                None => query,
+            });
+        }
+        match query_promise {
+            Some(promise) => promise
+                .then(ext_self::callback_validate_batch(
+                    report,
+                    env::current_account_id(),
+                    NO_DEPOSIT,
+                    GAS_FOR_VALIDATE_BATCH_CALLBACK,
+                ))
+                .into(),
+            None => {
+                report.valid = batch_is_valid(&report);
+                PromiseOrValue::Value(report)
+            }
+        }
+    }
+
+    #[private]
+    pub fn callback_validate_batch(&self, report: BatchReport) -> BatchReport {
+        let mut report = report;
+        let queried = report.receivers.iter_mut().filter(|r| r.valid_account_id);
+        for (i, receiver) in queried.enumerate() {
+            receiver.registered = Some(is_registered(i as u64));
+        }
+        report.valid = batch_is_valid(&report);
+        report
+    }
+
+    /// The report of the checks that need no cross-contract call, `valid` is not set yet.
+    fn batch_report(
+        &self,
+        receivers: Vec<String>,
+        amounts: Vec<U128>,
+        quota_owner_id: &AccountId,
+    ) -> BatchReport {
+        assert_eq!(receivers.len(), amounts.len(), "invalid parameters");
+        let quota_available = self.available_quota(quota_owner_id);
+        let mut seen: HashSet<String> = HashSet::new();
+        let reports: Vec<ReceiverReport> = receivers
+            .into_iter()
+            .zip(amounts)
+            .map(|(receiver_id, amount)| ReceiverReport {
+                valid_account_id: receiver_id.parse::<AccountId>().is_ok(),
+                duplicate: !seen.insert(receiver_id.clone()),
+                account_id: receiver_id,
+                amount,
+                registered: None,
+            })
+            .collect();
+        BatchReport {
+            total_amount: reports.iter().map(|r| r.amount.0).sum::<u128>().into(),
+            quota_required: reports.len() as u128,
+            quota_available,
+            valid: false,
+            receivers: reports,
+        }
+    }
+}
+
+fn batch_is_valid(report: &BatchReport) -> bool {
+    let enough_quota = report
+        .quota_available
+        .map(|quota| quota >= report.quota_required)
+        .unwrap_or(true);
+    enough_quota
+        && !report.receivers.is_empty()
+        && report.receivers.iter().all(|r| {
+            r.valid_account_id && !r.duplicate && r.amount.0 > 0 && r.registered != Some(false)
+        })
+}
@@ -0,0 +1,280 @@
+use crate::*;
+
+const GAS_FOR_WITHDRAW_VESTED_CALLBACK: Gas = Gas(5_000_000_000_000);
+/// Gas used by `callback_withdraw_vested` for each withdrawal.
+const GAS_PER_VESTED_WITHDRAWAL: Gas = Gas(1_000_000_000_000);
+/// Most vestings withdrawn in one call, so the transfers and their callback fit in one call.
+pub const MAX_VESTED_WITHDRAWALS: usize = 20;
+
+/// Vesting terms of a token distribution, given in the `ft_transfer_call` message.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VestingTerms {
+    /// Block timestamp in nanoseconds at which vesting starts.
+    pub start_at: U64,
+    /// Time after `start_at` in nanoseconds before anything can be withdrawn.
+    pub cliff: U64,
+    /// Time after `start_at` in nanoseconds at which everything is vested.
+    pub duration: U64,
+    /// Whether the sender can revoke the unvested tokens.
+    #[serde(default)]
+    pub revocable: bool,
+}
+
+/// Tokens held by the contract for `beneficiary_id`, unlocked linearly between `start_at` and
+/// `end_at` once `cliff_at` is reached.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Vesting {
+    pub beneficiary_id: AccountId,
+    pub sender_id: AccountId,
+    pub token_id: AccountId,
+    pub total: U128,
+    pub withdrawn: U128,
+    pub start_at: U64,
+    pub cliff_at: U64,
+    pub end_at: U64,
+    pub revocable: bool,
+}
+
+impl Vesting {
+    /// Amount vested at `timestamp`.
+    pub fn vested_at(&self, timestamp: u64) -> Balance {
+        if timestamp < self.cliff_at.0 {
+            0
+        } else if timestamp >= self.end_at.0 {
+            self.total.0
+        } else {
+            let elapsed = (timestamp - self.start_at.0) as u128;
+            let duration = (self.end_at.0 - self.start_at.0) as u128;
+            self.total.0 * elapsed / duration
+        }
+    }
+
+    /// Amount that can be withdrawn now.
+    pub fn withdrawable(&self) -> Balance {
+        self.vested_at(env::block_timestamp()) - self.withdrawn.0
+    }
+}
+
+/// A vesting with its id and the amounts vested and withdrawable now.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VestingView {
+    pub vesting_id: u64,
+    #[serde(flatten)]
+    pub vesting: Vesting,
+    pub vested: U128,
+    pub withdrawable: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Withdraw the vested tokens of `vesting_ids` of `env::predecessor_account_id`, see
+    /// `vesting_of`. Each vesting is sent with its own `ft_transfer`, vestings with nothing to
+    /// withdraw are skipped.
+    ///
+    /// Requirements:
+    /// - `vesting_ids` must hold 1 to `MAX_VESTED_WITHDRAWALS` vestings of the caller.
+    /// - Enough gas must be attached for the transfers, see `withdraw_vested_gas`.
+    pub fn withdraw_vested(&mut self, vesting_ids: Vec<u64>) -> Promise {
+        self.assert_not_paused(Feature::DistributeFt);
+        assert!(
+            !vesting_ids.is_empty() && vesting_ids.len() <= MAX_VESTED_WITHDRAWALS,
+            "Withdraw 1 to {} vestings at a time",
+            MAX_VESTED_WITHDRAWALS
+        );
+        let required_gas = withdraw_vested_gas(vesting_ids.len() as u64);
+        assert!(
+            env::prepaid_gas() - env::used_gas() >= required_gas,
+            "Not enough gas attached, {} withdrawals need {} gas",
+            vesting_ids.len(),
+            required_gas.0
+        );
+        let account_id = env::predecessor_account_id();
+        let mut withdrawals: Vec<(u64, U128)> = Vec::new();
+        let mut withdraw_promise: Option<Promise> = None;
+        for vesting_id in vesting_ids {
+            let mut vesting = self.vestings.get(&vesting_id).expect("Vesting not found");
+            assert_eq!(
+                vesting.beneficiary_id, account_id,
+                "Only the beneficiary can withdraw the vesting"
+            );
+            let amount = vesting.withdrawable();
+            if amount == 0 || withdrawals.iter().any(|(id, _)| *id == vesting_id) {
+                continue;
+            }
+            vesting.withdrawn = (vesting.withdrawn.0 + amount).into();
+            self.vestings.insert(&vesting_id, &vesting);
+            withdrawals.push((vesting_id, amount.into()));
+            let transfer = ext_ft::ft_transfer(
+                account_id.clone(),
+                amount.into(),
+                Some(String::from("")),
+                vesting.token_id,
+                1, // yocto NEAR to attach
+                GAS_FOR_FT_TRANSFER,
+            );
+            withdraw_promise = Some(match withdraw_promise {
+                Some(promise) => promise.and(transfer),
+                None => transfer,
+            });
+        }
+        let callback_gas = withdraw_vested_callback_gas(withdrawals.len() as u64);
+        withdraw_promise
+            .unwrap_or_else(|| env::panic_str("Nothing to withdraw"))
+            .then(ext_self::callback_withdraw_vested(
+                account_id,
+                withdrawals,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                callback_gas,
+            ))
+    }
+
+    #[private]
+    pub fn callback_withdraw_vested(
+        &mut self,
+        account_id: AccountId,
+        withdrawals: Vec<(u64, U128)>,
+    ) {
+        assert_eq!(env::promise_results_count(), withdrawals.len() as u64);
+        for (i, (vesting_id, amount)) in withdrawals.into_iter().enumerate() {
+            let mut vesting = match self.vestings.get(&vesting_id) {
+                Some(vesting) => vesting,
+                None => continue,
+            };
+            match env::promise_result(i as u64) {
+                PromiseResult::Successful(_) => {
+                    if vesting.withdrawn == vesting.total {
+                        self.remove_vesting(&account_id, vesting_id);
+                    }
+                }
+                _ => {
+                    vesting.withdrawn = (vesting.withdrawn.0 - amount.0).into();
+                    self.vestings.insert(&vesting_id, &vesting);
+                }
+            }
+        }
+    }
+
+    /// Stop `vesting_id` and send the tokens not vested yet back to the sender. The vested
+    /// tokens stay withdrawable by the beneficiary. Unvested tokens that cannot be sent stay
+    /// claimable by the sender with `claim_token_refund`.
+    ///
+    /// Requirements:
+    /// - The caller must be the sender of the vesting.
+    /// - The vesting must be revocable.
+    pub fn revoke_vesting(&mut self, vesting_id: u64) {
+        let mut vesting = self.vestings.get(&vesting_id).expect("Vesting not found");
+        assert_eq!(
+            env::predecessor_account_id(),
+            vesting.sender_id,
+            "Only the sender can revoke the vesting"
+        );
+        assert!(vesting.revocable, "Vesting is not revocable");
+        let now = env::block_timestamp();
+        let vested = vesting.vested_at(now);
+        let unvested = vesting.total.0 - vested;
+        vesting.total = vested.into();
+        vesting.cliff_at = vesting.cliff_at.0.min(now).into();
+        vesting.end_at = now.max(vesting.start_at.0).into();
+        vesting.revocable = false;
+        if vesting.withdrawn == vesting.total {
+            let beneficiary_id = vesting.beneficiary_id.clone();
+            self.remove_vesting(&beneficiary_id, vesting_id);
+        } else {
+            self.vestings.insert(&vesting_id, &vesting);
+        }
+        self.refund_funds(Some(vesting.token_id), &vesting.sender_id, unvested);
+    }
+
+    /// Return the vestings of `account_id`, paginated.
+    pub fn vesting_of(
+        &self,
+        account_id: AccountId,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<VestingView> {
+        let now = env::block_timestamp();
+        self.vesting_ids
+            .get(&account_id)
+            .unwrap_or_default()
+            .into_iter()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(u64::MAX) as usize)
+            .map(|vesting_id| {
+                let vesting = self.vestings.get(&vesting_id).unwrap();
+                VestingView {
+                    vesting_id,
+                    vested: vesting.vested_at(now).into(),
+                    withdrawable: vesting.withdrawable().into(),
+                    vesting,
+                }
+            })
+            .collect()
+    }
+
+    /// Hold `amount[i]` of the tokens sent by `sender_id` for `receivers[i]` under `terms`. The
+    /// sender pays the storage from its storage balance.
+    pub(crate) fn create_vestings(
+        &mut self,
+        sender_id: &AccountId,
+        receivers: Vec<AccountId>,
+        amount: Vec<U128>,
+        terms: VestingTerms,
+    ) {
+        assert!(terms.duration.0 > 0, "duration must be positive");
+        assert!(
+            terms.cliff.0 <= terms.duration.0,
+            "cliff must not exceed duration"
+        );
+        let initial_storage_usage = env::storage_usage();
+        let token_id = env::predecessor_account_id();
+        for (beneficiary_id, total) in receivers.into_iter().zip(amount) {
+            let vesting_id = self.next_vesting_id;
+            self.next_vesting_id += 1;
+            self.vestings.insert(
+                &vesting_id,
+                &Vesting {
+                    beneficiary_id: beneficiary_id.clone(),
+                    sender_id: sender_id.clone(),
+                    token_id: token_id.clone(),
+                    total,
+                    withdrawn: 0.into(),
+                    start_at: terms.start_at,
+                    cliff_at: (terms.start_at.0 + terms.cliff.0).into(),
+                    end_at: (terms.start_at.0 + terms.duration.0).into(),
+                    revocable: terms.revocable,
+                },
+            );
+            let mut vesting_ids = self.vesting_ids.get(&beneficiary_id).unwrap_or_default();
+            vesting_ids.push(vesting_id);
+            self.vesting_ids.insert(&beneficiary_id, &vesting_ids);
+        }
+        self.charge_storage(sender_id, initial_storage_usage);
+    }
+
+    fn remove_vesting(&mut self, beneficiary_id: &AccountId, vesting_id: u64) {
+        let initial_storage_usage = env::storage_usage();
+        let vesting = self.vestings.remove(&vesting_id).unwrap();
+        let mut vesting_ids = self.vesting_ids.get(beneficiary_id).unwrap_or_default();
+        vesting_ids.retain(|id| *id != vesting_id);
+        if vesting_ids.is_empty() {
+            self.vesting_ids.remove(beneficiary_id);
+        } else {
+            self.vesting_ids.insert(beneficiary_id, &vesting_ids);
+        }
+        self.record_storage(&vesting.sender_id, initial_storage_usage);
+    }
+}
+
+/// Gas of `callback_withdraw_vested` for `withdrawals` withdrawals.
+fn withdraw_vested_callback_gas(withdrawals: u64) -> Gas {
+    GAS_FOR_WITHDRAW_VESTED_CALLBACK + GAS_PER_VESTED_WITHDRAWAL * withdrawals
+}
+
+/// Gas `withdraw_vested` needs after its own execution for `withdrawals` withdrawals.
+pub(crate) fn withdraw_vested_gas(withdrawals: u64) -> Gas {
+    GAS_FOR_FT_TRANSFER * withdrawals + withdraw_vested_callback_gas(withdrawals)
+}
@@ -0,0 +1,312 @@
+use crate::*;
+
+/// Storage deposit of one account on `wrap.near`.
+pub const DEFAULT_WRAP_STORAGE_DEPOSIT: Balance = 1_250_000_000_000_000_000_000;
+const GAS_FOR_NEAR_DEPOSIT: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_NEAR_WITHDRAW: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_WRAP_NEAR_CALLBACK: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_WNEAR_REGISTERED_CALLBACK: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_WNEAR_TRANSFER_CALLBACK: Gas = Gas(20_000_000_000_000);
+const GAS_FOR_UNWRAP_REFUND_CALLBACK: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_WRAP_TOKEN_REGISTERED_CALLBACK: Gas = Gas(5_000_000_000_000);
+/// Gas each callback of a `wrap_near` batch uses per receiver, on top of its base gas.
+const GAS_PER_WNEAR_RECEIVER: Gas = Gas(1_000_000_000_000);
+/// Most receivers of a `wrap_near` batch, so the whole callback chain fits in the prepaid gas.
+pub const MAX_WNEAR_RECEIVERS: usize = 20;
+
+#[ext_contract(ext_wrap)]
+pub trait WrappedNear {
+    fn near_deposit(&mut self);
+    fn near_withdraw(&mut self, amount: U128);
+}
+
+#[near_bindgen]
+impl Contract {
+    /// A payable method to set the wrapped NEAR contract used by `wrap_near` distributions and the
+    /// storage deposit paid for each receiver that is not registered on it. The contract registers
+    /// itself on `wrap_token_id` with `storage_deposit` of the attached deposit, the rest is
+    /// refunded. `wrap_near` distributions are accepted once the registration succeeded.
+    ///
+    /// Requirements:
+    /// - The caller must be contract owner.
+    /// - The attached deposit must cover `storage_deposit`.
+    #[payable]
+    pub fn set_wrap_token(&mut self, wrap_token_id: AccountId, storage_deposit: U128) -> Promise {
+        self.assert_owner();
+        let deposit = env::attached_deposit();
+        assert!(
+            deposit >= storage_deposit.0,
+            "Attach the storage deposit to register the contract on the wrap token"
+        );
+        if deposit > storage_deposit.0 {
+            self.refund_near(&self.owner_id, deposit - storage_deposit.0);
+        }
+        self.wrap_token_id = wrap_token_id.clone();
+        self.wrap_storage_deposit = storage_deposit.0;
+        self.wrap_token_registered = false;
+        ext_ft::storage_deposit(
+            Some(env::current_account_id()),
+            Some(true),
+            wrap_token_id.clone(),
+            storage_deposit.0,
+            GAS_FOR_STORAGE_DEPOSIT,
+        )
+        .then(ext_self::callback_wrap_token_registered(
+            wrap_token_id,
+            storage_deposit,
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_WRAP_TOKEN_REGISTERED_CALLBACK,
+        ))
+    }
+
+    #[private]
+    pub fn callback_wrap_token_registered(
+        &mut self,
+        wrap_token_id: AccountId,
+        storage_deposit: U128,
+    ) {
+        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
+        if !matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+            log!("registration on {} failed", wrap_token_id);
+            self.refund_near(&self.owner_id, storage_deposit.0);
+            return;
+        }
+        // the wrap token may have been replaced while registering
+        if wrap_token_id == self.wrap_token_id {
+            self.wrap_token_registered = true;
+        }
+    }
+
+    /// Return the wrapped NEAR contract and the storage deposit per receiver.
+    pub fn wrap_token(&self) -> (AccountId, U128) {
+        (self.wrap_token_id.clone(), self.wrap_storage_deposit.into())
+    }
+
+    #[private]
+    pub fn callback_wrap_near(
+        &mut self,
+        receivers: Vec<AccountId>,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+        storage_deposit: U128,
+    ) {
+        assert_eq!(env::promise_results_count(), receivers.len() as u64 + 1);
+        let total_amount: Balance = amount.iter().map(|x| x.0).sum();
+        if !matches!(env::promise_result(0), PromiseResult::Successful(_)) {
+            log!("wrapping NEAR failed");
+            self.refund_near(&charge.spender_id, total_amount + storage_deposit.0);
+            self.refund_quota(&charge, charge.amount);
+            return;
+        }
+        let registrations: Option<Vec<bool>> = (0..receivers.len())
+            .map(|i| registration_result(i as u64 + 1))
+            .collect();
+        let registrations = match registrations {
+            Some(registrations) => registrations,
+            None => {
+                log!("checking the wNEAR registrations failed");
+                self.refund_near(&charge.spender_id, storage_deposit.0);
+                self.refund_quota(&charge, charge.amount);
+                self.unwrap_refund(charge.spender_id, total_amount);
+                return;
+            }
+        };
+        let unregistered: Vec<AccountId> = receivers
+            .iter()
+            .zip(registrations)
+            .filter(|(_, registered)| !registered)
+            .map(|(receiver_id, _)| receiver_id.clone())
+            .collect();
+        let registration_fee = self.wrap_storage_deposit * unregistered.len() as u128;
+        if storage_deposit.0 > registration_fee {
+            self.refund_near(&charge.spender_id, storage_deposit.0 - registration_fee);
+        }
+        if unregistered.is_empty() {
+            self.transfer_wnear(receivers, amount, charge);
+            return;
+        }
+        let receivers_len = receivers.len() as u64;
+
+        let mut registration_promise: Option<Promise> = None;
+        for receiver_id in unregistered.iter() {
+            let registration = ext_ft::storage_deposit(
+                Some(receiver_id.clone()),
+                Some(true),
+                self.wrap_token_id.clone(),
+                self.wrap_storage_deposit,
+                GAS_FOR_STORAGE_DEPOSIT,
+            );
+            registration_promise = Some(match registration_promise {
+                Some(promise) => promise.and(registration),
+                None => registration,
+            });
+        }
+        registration_promise
+            .unwrap()
+            .then(ext_self::callback_wnear_registered(
+                receivers,
+                amount,
+                charge,
+                unregistered.len() as u64,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                wnear_registered_callback_gas(receivers_len),
+            ));
+    }
+
+    #[private]
+    pub fn callback_wnear_registered(
+        &mut self,
+        receivers: Vec<AccountId>,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+        registrations: u64,
+    ) {
+        assert_eq!(env::promise_results_count(), registrations);
+        let failed_registrations = (0..registrations)
+            .filter(|i| !matches!(env::promise_result(*i), PromiseResult::Successful(_)))
+            .count() as u128;
+        if failed_registrations > 0 {
+            // the deposits of failed registrations are refunded to this contract
+            self.refund_near(
+                &charge.spender_id,
+                self.wrap_storage_deposit * failed_registrations,
+            );
+        }
+        self.transfer_wnear(receivers, amount, charge);
+    }
+
+    #[private]
+    pub fn callback_wnear_transfer(&mut self, amount: Vec<U128>, charge: QuotaCharge) {
+        let (refund, total_failed_transfer) = failed_transfers(&amount);
+        if total_failed_transfer > 0 {
+            self.refund_quota(&charge, total_failed_transfer);
+            self.unwrap_refund(charge.spender_id, refund);
+        }
+    }
+
+    /// Refund the NEAR unwrapped for a failed batch or failed wNEAR transfers, or the wNEAR if
+    /// unwrapping failed.
+    #[private]
+    pub fn callback_unwrap_refund(&mut self, sender_id: AccountId, amount: U128) {
+        assert_eq!(env::promise_results_count(), 1, "This is a callback method");
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => self.refund_near(&sender_id, amount.0),
+            _ => self.refund_funds(Some(self.wrap_token_id.clone()), &sender_id, amount.0),
+        }
+    }
+
+    /// Wrap the NEAR of a batch and send it as wNEAR, registering receivers on the wrapped NEAR
+    /// contract first. `storage_deposit` covers the registration of every receiver, the part of
+    /// registered receivers is refunded.
+    pub(crate) fn distribute_wnear(
+        &mut self,
+        receivers: Vec<AccountId>,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+        storage_deposit: Balance,
+    ) {
+        assert!(
+            self.wrap_token_registered,
+            "The contract is not registered on the wrap token, see set_wrap_token"
+        );
+        assert!(
+            receivers.len() <= MAX_WNEAR_RECEIVERS,
+            "wrap_near batches are limited to {} receivers",
+            MAX_WNEAR_RECEIVERS
+        );
+        let required_gas = distribute_wnear_gas(receivers.len() as u64);
+        assert!(
+            env::prepaid_gas() - env::used_gas() >= required_gas,
+            "Not enough gas attached, {} receivers need {} gas",
+            receivers.len(),
+            required_gas.0
+        );
+        let total_amount: Balance = amount.iter().map(|x| x.0).sum();
+        let mut wrap_promise = ext_wrap::near_deposit(
+            self.wrap_token_id.clone(),
+            total_amount,
+            GAS_FOR_NEAR_DEPOSIT,
+        );
+        for receiver_id in receivers.iter() {
+            wrap_promise = wrap_promise.and(ext_ft::storage_balance_of(
+                receiver_id.clone(),
+                self.wrap_token_id.clone(),
+                NO_DEPOSIT,
+                GAS_FOR_STORAGE_BALANCE_OF,
+            ));
+        }
+        let receivers_len = receivers.len() as u64;
+        wrap_promise.then(ext_self::callback_wrap_near(
+            receivers,
+            amount,
+            charge,
+            storage_deposit.into(),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            wrap_near_callback_gas(receivers_len),
+        ));
+    }
+
+    /// Unwrap `amount` of wNEAR held by the contract and refund it to `sender_id`.
+    fn unwrap_refund(&self, sender_id: AccountId, amount: Balance) {
+        ext_wrap::near_withdraw(
+            amount.into(),
+            self.wrap_token_id.clone(),
+            1, // yocto NEAR to attach
+            GAS_FOR_NEAR_WITHDRAW,
+        )
+        .then(ext_self::callback_unwrap_refund(
+            sender_id,
+            amount.into(),
+            env::current_account_id(),
+            NO_DEPOSIT,
+            GAS_FOR_UNWRAP_REFUND_CALLBACK,
+        ));
+    }
+
+    fn transfer_wnear(
+        &mut self,
+        receivers: Vec<AccountId>,
+        amount: Vec<U128>,
+        charge: QuotaCharge,
+    ) {
+        let callback_gas = wnear_transfer_callback_gas(receivers.len() as u64);
+        ft_transfers(&self.wrap_token_id, &receivers, &amount).then(
+            ext_self::callback_wnear_transfer(
+                amount,
+                charge,
+                env::current_account_id(),
+                NO_DEPOSIT,
+                callback_gas,
+            ),
+        );
+    }
+}
+
+/// Gas of `callback_wnear_transfer` for a batch of `receivers`.
+fn wnear_transfer_callback_gas(receivers: u64) -> Gas {
+    GAS_FOR_WNEAR_TRANSFER_CALLBACK + GAS_PER_WNEAR_RECEIVER * receivers
+}
+
+/// Gas of `callback_wnear_registered`, including the wNEAR transfers it makes.
+fn wnear_registered_callback_gas(receivers: u64) -> Gas {
+    GAS_FOR_WNEAR_REGISTERED_CALLBACK
+        + (GAS_PER_WNEAR_RECEIVER + GAS_FOR_FT_TRANSFER) * receivers
+        + wnear_transfer_callback_gas(receivers)
+}
+
+/// Gas of `callback_wrap_near`, including the registrations and transfers that follow it.
+fn wrap_near_callback_gas(receivers: u64) -> Gas {
+    GAS_FOR_WRAP_NEAR_CALLBACK
+        + (GAS_PER_WNEAR_RECEIVER + GAS_FOR_STORAGE_DEPOSIT) * receivers
+        + wnear_registered_callback_gas(receivers)
+}
+
+/// Gas `distribute_wnear` hands to the promises of a batch of `receivers`.
+pub(crate) fn distribute_wnear_gas(receivers: u64) -> Gas {
+    GAS_FOR_NEAR_DEPOSIT
+        + GAS_FOR_STORAGE_BALANCE_OF * receivers
+        + wrap_near_callback_gas(receivers)
+}
@@ -38,7 +38,8 @@ fn simulate_distribute_near() {
         root,
         bs.distribute_near(
             vec![alice.account_id(), alice.account_id()],
-            vec![transfer_amount.into(), transfer_amount.into()]
+            vec![transfer_amount.into(), transfer_amount.into()],
+            None
         ),
         transfer_amount * 2,
         DEFAULT_GAS / 2
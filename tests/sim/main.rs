@@ -1,2 +1,3 @@
 mod utils;
 mod macros;
+mod swap;
@@ -0,0 +1,175 @@
+use near_sdk::json_types::U128;
+use near_sdk::serde_json::json;
+use near_sdk_sim::{call, to_yocto, UserAccount, DEFAULT_GAS};
+
+use crate::utils::init_with_macros as init;
+
+// Load in contract bytes at runtime
+near_sdk_sim::lazy_static_include::lazy_static_include_bytes! {
+    FT_WASM_BYTES => "res/fungible_token.wasm",
+    POOL_WASM_BYTES => "target/res/mock_pool.wasm",
+}
+
+const FT_OUT_ID: &str = "ft_out";
+const POOL_ID: &str = "pool";
+
+// Register `account_id` with the FT contract `token_id`
+fn register(user: &UserAccount, token_id: &str, account_id: &str) {
+    user.call(
+        token_id.parse().unwrap(),
+        "storage_deposit",
+        &json!({ "account_id": account_id }).to_string().into_bytes(),
+        DEFAULT_GAS / 2,
+        near_sdk::env::storage_byte_cost() * 125, // attached deposit
+    )
+    .assert_success();
+}
+
+fn ft_balance(user: &UserAccount, token_id: &str, account_id: &str) -> u128 {
+    let balance: U128 = user
+        .view(
+            token_id.parse().unwrap(),
+            "ft_balance_of",
+            &json!({ "account_id": account_id }).to_string().into_bytes(),
+        )
+        .unwrap_json();
+    balance.0
+}
+
+/// Deploy an output token and a mock pool swapping at 50%, and enable swaps on the bulk sender.
+fn init_swap(initial_balance: u128) -> (UserAccount, UserAccount, UserAccount, UserAccount) {
+    let (root, ft, bs, alice) = init(initial_balance);
+
+    let ft_out = root.deploy(&FT_WASM_BYTES, FT_OUT_ID.parse().unwrap(), to_yocto("10"));
+    root.call(
+        ft_out.account_id(),
+        "new_default_meta",
+        &json!({
+            "owner_id": root.account_id(),
+            "total_supply": U128::from(initial_balance),
+        })
+        .to_string()
+        .into_bytes(),
+        DEFAULT_GAS / 2,
+        0,
+    )
+    .assert_success();
+
+    let pool = root.deploy(&POOL_WASM_BYTES, POOL_ID.parse().unwrap(), to_yocto("10"));
+    root.call(
+        pool.account_id(),
+        "new",
+        &json!({ "rate": 50 }).to_string().into_bytes(),
+        DEFAULT_GAS / 2,
+        0,
+    )
+    .assert_success();
+
+    for token_id in [ft.account_id().as_str(), FT_OUT_ID] {
+        register(&root, token_id, POOL_ID);
+        register(&root, token_id, bs.account_id().as_str());
+    }
+    register(&root, FT_OUT_ID, alice.account_id().as_str());
+    // the pool pays withdrawals of swap outputs from its own balance
+    root.call(
+        ft_out.account_id(),
+        "ft_transfer",
+        &json!({
+            "receiver_id": POOL_ID,
+            "amount": U128::from(to_yocto("1000")),
+        })
+        .to_string()
+        .into_bytes(),
+        DEFAULT_GAS / 2,
+        1,
+    )
+    .assert_success();
+
+    call!(root, bs.set_dex(Some(pool.account_id()))).assert_success();
+    call!(root, bs.set_fee_discount(root.account_id(), 100)).assert_success();
+
+    (root, ft.user_account, alice, pool)
+}
+
+fn swap_distribute(
+    root: &UserAccount,
+    ft: &UserAccount,
+    alice: &UserAccount,
+    min_amount_out: u128,
+) {
+    root.call(
+        ft.account_id(),
+        "ft_transfer_call",
+        &json!({
+            "receiver_id": "bulksender",
+            "amount": U128::from(to_yocto("100")),
+            "msg": json!({
+                "receivers": [alice.account_id()],
+                "amount": [U128::from(to_yocto("40"))],
+                "swap": {
+                    "pool_id": 0,
+                    "token_out": FT_OUT_ID,
+                    "min_amount_out": U128::from(min_amount_out),
+                },
+            })
+            .to_string(),
+        })
+        .to_string()
+        .into_bytes(),
+        DEFAULT_GAS,
+        1,
+    )
+    .assert_success();
+}
+
+#[test]
+fn simulate_swap_distribution() {
+    let initial_balance = to_yocto("100000");
+    let (root, ft, alice, _pool) = init_swap(initial_balance);
+    let root_out_balance = ft_balance(&root, FT_OUT_ID, root.account_id().as_str());
+
+    swap_distribute(&root, &ft, &alice, to_yocto("50"));
+
+    // 100 in swaps to 50 out, 40 to alice and the 10 left back to the sender
+    assert_eq!(
+        ft_balance(&root, ft.account_id().as_str(), root.account_id().as_str()),
+        initial_balance - to_yocto("100")
+    );
+    assert_eq!(
+        ft_balance(&root, FT_OUT_ID, alice.account_id().as_str()),
+        to_yocto("40")
+    );
+    assert_eq!(
+        ft_balance(&root, FT_OUT_ID, root.account_id().as_str()),
+        root_out_balance + to_yocto("10")
+    );
+}
+
+#[test]
+fn simulate_failed_swap_refunds_sender() {
+    let initial_balance = to_yocto("100000");
+    let (root, ft, alice, pool) = init_swap(initial_balance);
+
+    // the pool returns 50, less than min_amount_out
+    swap_distribute(&root, &ft, &alice, to_yocto("60"));
+
+    assert_eq!(
+        ft_balance(&root, ft.account_id().as_str(), root.account_id().as_str()),
+        initial_balance
+    );
+    assert_eq!(ft_balance(&root, FT_OUT_ID, alice.account_id().as_str()), 0);
+    let left_on_pool: U128 = root
+        .view(
+            "bulksender".parse().unwrap(),
+            "dex_refund",
+            &json!({
+                "account_id": root.account_id(),
+                "dex_id": pool.account_id(),
+                "token_id": ft.account_id(),
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .unwrap_json();
+    assert_eq!(left_on_pool.0, 0);
+}